@@ -0,0 +1,5 @@
+#![cfg_attr(not(test), no_std)]
+
+pub mod math;
+pub mod storage;
+pub mod stpm;
@@ -0,0 +1,83 @@
+/// integer square root, rounded down
+pub fn isqrt(value: u64) -> u64 {
+    if value < 2 {
+        return value;
+    }
+
+    // newton iteration, starting above the result
+    let mut x = 1u64 << (64 - value.leading_zeros()).div_ceil(2);
+    loop {
+        let y = (x + value / x) / 2;
+        if y >= x {
+            return x;
+        }
+        x = y;
+    }
+}
+
+/// number of points of the FFT
+pub const FFT_LEN: usize = 512;
+const FFT_STAGES: u32 = FFT_LEN.trailing_zeros();
+
+/// sin(2 pi k / FFT_LEN) for the first quarter wave, in Q15
+const SINE_Q15: [i32; FFT_LEN / 4 + 1] = [
+    0, 402, 804, 1206, 1608, 2009, 2410, 2811, 3212, 3612, 4011, 4410, 4808, 5205, 5602, 5998,
+    6393, 6786, 7179, 7571, 7962, 8351, 8739, 9126, 9512, 9896, 10278, 10659, 11039, 11417, 11793,
+    12167, 12539, 12910, 13279, 13645, 14010, 14372, 14732, 15090, 15446, 15800, 16151, 16499,
+    16846, 17189, 17530, 17869, 18204, 18537, 18868, 19195, 19519, 19841, 20159, 20475, 20787,
+    21096, 21403, 21705, 22005, 22301, 22594, 22884, 23170, 23452, 23731, 24007, 24279, 24547,
+    24811, 25072, 25329, 25582, 25832, 26077, 26319, 26556, 26790, 27019, 27245, 27466, 27683,
+    27896, 28105, 28310, 28510, 28706, 28898, 29085, 29268, 29447, 29621, 29791, 29956, 30117,
+    30273, 30424, 30571, 30714, 30852, 30985, 31113, 31237, 31356, 31470, 31580, 31685, 31785,
+    31880, 31971, 32057, 32137, 32213, 32285, 32351, 32412, 32469, 32521, 32567, 32609, 32646,
+    32678, 32705, 32728, 32745, 32757, 32765, 32767,
+];
+
+/// cos and sin of 2 pi k / FFT_LEN for k < FFT_LEN / 2, in Q15
+fn twiddle(k: usize) -> (i32, i32) {
+    const QUARTER: usize = FFT_LEN / 4;
+    if k <= QUARTER {
+        (SINE_Q15[QUARTER - k], SINE_Q15[k])
+    } else {
+        (-SINE_Q15[k - QUARTER], SINE_Q15[2 * QUARTER - k])
+    }
+}
+
+/// in-place radix-2 FFT in fixed point.
+/// every stage divides by two to avoid overflows, so the result is the DFT
+/// divided by FFT_LEN. inputs should stay within +-2^24.
+pub fn fft(re: &mut [i32; FFT_LEN], im: &mut [i32; FFT_LEN]) {
+    // bit reversal permutation
+    for i in 0..FFT_LEN {
+        let j = i.reverse_bits() >> (usize::BITS - FFT_STAGES);
+        if j > i {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= FFT_LEN {
+        let half = len / 2;
+        let step = FFT_LEN / len;
+        for start in (0..FFT_LEN).step_by(len) {
+            for k in 0..half {
+                let (cos, sin) = twiddle(k * step);
+                let (a, b) = (start + k, start + k + half);
+
+                // b * e^(-j phi)
+                let br = re[b] as i64;
+                let bi = im[b] as i64;
+                let tr = ((br * cos as i64 + bi * sin as i64) >> 15) as i32;
+                let ti = ((bi * cos as i64 - br * sin as i64) >> 15) as i32;
+
+                let (ar, ai) = (re[a], im[a]);
+                re[a] = (ar + tr) >> 1;
+                im[a] = (ai + ti) >> 1;
+                re[b] = (ar - tr) >> 1;
+                im[b] = (ai - ti) >> 1;
+            }
+        }
+        len *= 2;
+    }
+}
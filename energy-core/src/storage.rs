@@ -0,0 +1,585 @@
+//! access to the I2C EEPROM / FRAM and the layout of the config in the
+//! EEPROM: a header with the config version, followed by one section per
+//! config struct. every section is prefixed with its length and has its own
+//! CRC, so a section that can't be read (e.g. because its struct changed) only
+//! resets itself.
+
+use crc::{Crc, CRC_32_ISCSI};
+use embassy_time::{Duration, Timer};
+use embedded_hal_async::i2c::I2c;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::stpm::energy::EnergyTotals;
+
+pub const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+
+/// the ESP32 i2c driver reads at most 32 and writes at most 31 bytes at once,
+/// so memories are accessed in pages of this size (two address bytes + data)
+pub const PAGE_SIZE: usize = 16;
+
+/// reads `buffer` from a memory with two address bytes, starting at `addr`
+pub async fn read_memory<I: I2c>(
+    i2c: &mut I,
+    device: u8,
+    addr: u16,
+    buffer: &mut [u8],
+) -> Result<(), I::Error> {
+    let mut addr = addr;
+    for page in buffer.chunks_mut(PAGE_SIZE) {
+        i2c.write_read(device, &addr.to_be_bytes(), page).await?;
+        addr += page.len() as u16;
+    }
+    Ok(())
+}
+
+/// writes `data` to a memory with two address bytes, starting at `addr`.
+/// the pages are aligned to PAGE_SIZE so they never cross a page of the
+/// memory, `write_time` is waited after every page (zero for FRAM).
+pub async fn write_memory<I: I2c>(
+    i2c: &mut I,
+    device: u8,
+    addr: u16,
+    data: &[u8],
+    write_time: Duration,
+) -> Result<(), I::Error> {
+    let mut buffer = [0u8; PAGE_SIZE + 2];
+    let mut addr = addr;
+    let mut data = data;
+    while !data.is_empty() {
+        let len = data.len().min(PAGE_SIZE - addr as usize % PAGE_SIZE);
+        buffer[..2].copy_from_slice(&addr.to_be_bytes());
+        buffer[2..len + 2].copy_from_slice(&data[..len]);
+        i2c.write(device, &buffer[..len + 2]).await?;
+
+        // wait for write to finish internally
+        if write_time > Duration::from_ticks(0) {
+            Timer::after(write_time).await;
+        }
+
+        addr += len as u16;
+        data = &data[len..];
+    }
+    Ok(())
+}
+
+/// maximum size of the serialized energy totals, 6 per channel and 2 of the
+/// combined channel (value and remainder per total, 10 bytes per i64 varint
+/// + CRC)
+pub const ACCUMULATOR_LEN: usize = (2 * 6 + 2) * 2 * 10 + 4;
+
+/// size of the accumulator written by the firmware before the energy totals:
+/// the active energy of both channels in raw register units at current gain
+/// 16 (`[i64; 2]`), zero padded
+pub const LEGACY_ACCUMULATOR_LEN: usize = 20;
+
+#[derive(Debug)]
+pub enum AccumulatorError<E> {
+    I2c(E),
+    /// never written, every byte is 0x00 or 0xff
+    Empty,
+    /// CRC mismatch or invalid data
+    Corrupted,
+}
+
+/// reads the energy totals of both channels and their sum from a FRAM with
+/// two address bytes. the accumulator of the old firmware is converted with
+/// the energy LSB of each channel, see `EnergyTotals::from_legacy`.
+pub async fn read_accumulator<I: I2c>(
+    i2c: &mut I,
+    device: u8,
+    legacy_lsb: [i64; 2],
+) -> Result<EnergyTotals, AccumulatorError<I::Error>> {
+    let mut buffer = [0u8; ACCUMULATOR_LEN];
+    read_memory(i2c, device, 0, &mut buffer)
+        .await
+        .map_err(AccumulatorError::I2c)?;
+    if buffer.iter().all(|b| *b == 0x00) || buffer.iter().all(|b| *b == 0xff) {
+        return Err(AccumulatorError::Empty);
+    }
+    if let Ok(totals) = postcard::from_bytes_crc32(&buffer, CRC.digest()) {
+        return Ok(totals);
+    }
+    postcard::from_bytes_crc32(&buffer[..LEGACY_ACCUMULATOR_LEN], CRC.digest())
+        .map(|raw| EnergyTotals::from_legacy(raw, legacy_lsb))
+        .map_err(|_| AccumulatorError::Corrupted)
+}
+
+/// writes the energy totals of both channels and their sum, FRAM writes immediately so
+/// there is no need to wait between pages
+pub async fn write_accumulator<I: I2c>(
+    i2c: &mut I,
+    device: u8,
+    accumulator: &EnergyTotals,
+) -> Result<(), AccumulatorError<I::Error>> {
+    let mut buffer = [0u8; ACCUMULATOR_LEN];
+    let data = postcard::to_slice_crc32(accumulator, &mut buffer, CRC.digest())
+        .map_err(|_| AccumulatorError::Corrupted)?;
+    write_memory(i2c, device, 0, data, Duration::from_ticks(0))
+        .await
+        .map_err(AccumulatorError::I2c)
+}
+
+/// marks a versioned config, the unversioned config started with the mqtt
+/// section directly
+const MAGIC: [u8; 2] = *b"EM";
+const HEADER_LEN: usize = MAGIC.len() + 2;
+const LENGTH_LEN: usize = 2;
+
+pub struct SectionWriter<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> SectionWriter<'a> {
+    pub fn new(buffer: &'a mut [u8], version: u16) -> Option<Self> {
+        let header = buffer.get_mut(..HEADER_LEN)?;
+        header[..MAGIC.len()].copy_from_slice(&MAGIC);
+        header[MAGIC.len()..].copy_from_slice(&version.to_be_bytes());
+        Some(Self {
+            buffer,
+            len: HEADER_LEN,
+        })
+    }
+
+    pub fn write<T: Serialize>(&mut self, value: &T) -> Option<()> {
+        let buffer = self.buffer.get_mut(self.len..)?;
+        if buffer.len() < LENGTH_LEN {
+            return None;
+        }
+        let (length, data) = buffer.split_at_mut(LENGTH_LEN);
+        let n = postcard::to_slice_crc32(value, data, CRC.digest())
+            .ok()?
+            .len();
+        length.copy_from_slice(&u16::try_from(n).ok()?.to_be_bytes());
+        self.len += LENGTH_LEN + n;
+        Some(())
+    }
+
+    /// number of bytes written, including the header
+    pub fn finish(self) -> usize {
+        self.len
+    }
+}
+
+pub struct SectionReader<'a> {
+    version: u16,
+    buffer: &'a [u8],
+}
+
+impl<'a> SectionReader<'a> {
+    /// None if the buffer does not start with a config header
+    pub fn new(buffer: &'a [u8]) -> Option<Self> {
+        let header = buffer.get(..HEADER_LEN)?;
+        if header[..MAGIC.len()] != MAGIC {
+            return None;
+        }
+        Some(Self {
+            version: u16::from_be_bytes([header[2], header[3]]),
+            buffer: &buffer[HEADER_LEN..],
+        })
+    }
+
+    /// config version the buffer was written with
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    /// reads the next section, None if it is corrupted or does not match `T`.
+    /// the following sections can still be read unless the length prefix
+    /// itself is broken.
+    pub fn read<T: DeserializeOwned>(&mut self) -> Option<T> {
+        let length = self.buffer.get(..LENGTH_LEN)?;
+        let n = u16::from_be_bytes([length[0], length[1]]) as usize;
+        let Some(data) = self.buffer.get(LENGTH_LEN..LENGTH_LEN + n) else {
+            self.buffer = &[];
+            return None;
+        };
+        self.buffer = &self.buffer[LENGTH_LEN + n..];
+
+        match postcard::take_from_bytes_crc32::<T>(data, CRC.digest()) {
+            Ok((value, [])) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+/// reads the unversioned config written before the section layout: the
+/// config structs back to back, each with its own CRC but without a length
+/// prefix, so the first section that can't be read ends the config
+pub struct LegacyReader<'a> {
+    buffer: &'a [u8],
+}
+
+impl<'a> LegacyReader<'a> {
+    pub fn new(buffer: &'a [u8]) -> Self {
+        Self { buffer }
+    }
+
+    /// reads the next struct, `T` has to match the layout of the old version
+    pub fn read<T: DeserializeOwned>(&mut self) -> Option<T> {
+        match postcard::take_from_bytes_crc32::<T>(self.buffer, CRC.digest()) {
+            Ok((value, rest)) => {
+                self.buffer = rest;
+                Some(value)
+            }
+            Err(_) => {
+                self.buffer = &[];
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use embassy_futures::block_on;
+    use embedded_hal_async::i2c::{ErrorKind, ErrorType, Operation};
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::stpm::energy::{EnergyAccumulator, EnergyTotal, ENERGY_FRACTION_BITS};
+
+    const DEVICE: u8 = 0b101_0010;
+
+    /// I2C memory with two address bytes that rejects transfers the ESP32
+    /// driver can't do
+    pub(crate) struct Memory {
+        pub data: Vec<u8>,
+        pointer: usize,
+        pub transfers: usize,
+    }
+
+    impl Memory {
+        pub fn new(size: usize) -> Self {
+            Self {
+                data: vec![0xff; size],
+                pointer: 0,
+                transfers: 0,
+            }
+        }
+    }
+
+    impl ErrorType for Memory {
+        type Error = ErrorKind;
+    }
+
+    impl I2c for Memory {
+        async fn transaction(
+            &mut self,
+            address: u8,
+            operations: &mut [Operation<'_>],
+        ) -> Result<(), Self::Error> {
+            if address != DEVICE {
+                return Err(ErrorKind::Other);
+            }
+            for operation in operations {
+                self.transfers += 1;
+                match operation {
+                    Operation::Write(bytes) => {
+                        if bytes.len() > 31 || bytes.len() < 2 {
+                            return Err(ErrorKind::Other);
+                        }
+                        self.pointer = u16::from_be_bytes([bytes[0], bytes[1]]) as usize;
+                        for &b in &bytes[2..] {
+                            let len = self.data.len();
+                            self.data[self.pointer % len] = b;
+                            self.pointer += 1;
+                        }
+                    }
+                    Operation::Read(buffer) => {
+                        if buffer.len() > 32 {
+                            return Err(ErrorKind::Other);
+                        }
+                        for b in buffer.iter_mut() {
+                            *b = self.data[self.pointer % self.data.len()];
+                            self.pointer += 1;
+                        }
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn memory_round_trip() {
+        let mut memory = Memory::new(8192);
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+
+        block_on(write_memory(
+            &mut memory,
+            DEVICE,
+            0,
+            &data,
+            Duration::from_ticks(0),
+        ))
+        .unwrap();
+        assert_eq!(memory.data[..1000], data);
+        assert_eq!(memory.data[1000], 0xff);
+        assert_eq!(memory.transfers, 1000usize.div_ceil(PAGE_SIZE));
+
+        let mut read = vec![0; 1000];
+        block_on(read_memory(&mut memory, DEVICE, 0, &mut read)).unwrap();
+        assert_eq!(read, data);
+    }
+
+    #[test]
+    fn unaligned_write() {
+        let mut memory = Memory::new(256);
+        block_on(write_memory(
+            &mut memory,
+            DEVICE,
+            10,
+            &[1; 40],
+            Duration::from_ticks(0),
+        ))
+        .unwrap();
+        assert_eq!(memory.data[9], 0xff);
+        assert_eq!(memory.data[10..50], [1; 40]);
+        assert_eq!(memory.data[50], 0xff);
+        // 10..16, 16..32, 32..48, 48..50
+        assert_eq!(memory.transfers, 4);
+
+        let mut read = [0; 20];
+        block_on(read_memory(&mut memory, DEVICE, 40, &mut read)).unwrap();
+        assert_eq!(read[..10], [1; 10]);
+        assert_eq!(read[10..], [0xff; 10]);
+    }
+
+    /// largest serialized size of a total: value with the most varint bytes,
+    /// remainder just below 1 mWh
+    fn max_total(value: i64) -> EnergyTotal {
+        let mut total = EnergyTotal::default();
+        total.add(-1, 1);
+        total.value = value;
+        total
+    }
+
+    fn max_totals(value: i64) -> EnergyTotals {
+        EnergyTotals {
+            channels: [max_accumulator(value), max_accumulator(value)],
+            combined_import: max_total(value),
+            combined_export: max_total(value),
+        }
+    }
+
+    fn max_accumulator(value: i64) -> EnergyAccumulator {
+        EnergyAccumulator {
+            active: max_total(value),
+            fundamental: max_total(value),
+            reactive: max_total(value),
+            apparent: max_total(value),
+            active_import: max_total(value),
+            active_export: max_total(value),
+        }
+    }
+
+    #[test]
+    fn accumulator_survives_power_cycle() {
+        let mut fram = Memory::new(8192);
+        for value in [i64::MIN, i64::MAX, -1, 0] {
+            let accumulator = max_totals(value);
+            block_on(write_accumulator(&mut fram, DEVICE, &accumulator)).unwrap();
+
+            // after a power cycle only the FRAM content is left
+            let mut fram_after = Memory::new(0);
+            fram_after.data = fram.data.clone();
+            assert_eq!(block_on(read_accumulator(&mut fram_after, DEVICE, [1, 1])).unwrap(), accumulator);
+        }
+    }
+
+    #[test]
+    fn accumulator_len() {
+        let mut buffer = [0u8; 1024];
+        for value in [i64::MIN, i64::MAX] {
+            let accumulator = max_totals(value);
+            let data = postcard::to_slice_crc32(&accumulator, &mut buffer, CRC.digest()).unwrap();
+            assert!(data.len() <= ACCUMULATOR_LEN);
+        }
+    }
+
+    #[test]
+    fn new_fram_is_empty() {
+        for fill in [0x00, 0xff] {
+            let mut fram = Memory::new(8192);
+            fram.data.fill(fill);
+            assert!(matches!(
+                block_on(read_accumulator(&mut fram, DEVICE, [1, 1])),
+                Err(AccumulatorError::Empty)
+            ));
+        }
+    }
+
+    #[test]
+    fn garbage_is_corrupted() {
+        let mut fram = Memory::new(8192);
+        block_on(write_accumulator(&mut fram, DEVICE, &max_totals(-1))).unwrap();
+        fram.data[3] ^= 0x01;
+        assert!(matches!(
+            block_on(read_accumulator(&mut fram, DEVICE, [1, 1])),
+            Err(AccumulatorError::Corrupted)
+        ));
+    }
+
+    #[test]
+    fn legacy_accumulator() {
+        // the old firmware wrote the 20 byte buffer in one transfer
+        let lsb = [1 << ENERGY_FRACTION_BITS, 2 << ENERGY_FRACTION_BITS];
+        // up to the largest values that fit into it
+        for raw in [[0, 0], [123_456_789_012, -42], [i64::MAX >> 9, i64::MIN >> 9]] {
+            let mut fram = Memory::new(8192);
+            postcard::to_slice_crc32(&raw, &mut fram.data[..LEGACY_ACCUMULATOR_LEN], CRC.digest()).unwrap();
+            let totals = block_on(read_accumulator(&mut fram, DEVICE, lsb)).unwrap();
+            assert_eq!(totals, EnergyTotals::from_legacy(raw, lsb));
+            assert_eq!(totals.channels[1].active.value, raw[1] * 2);
+        }
+    }
+
+    #[test]
+    fn single_transfer_is_rejected() {
+        // the accumulator used to be written in one transfer
+        let mut memory = Memory::new(256);
+        assert!(block_on(memory.write(DEVICE, &[0; 40])).is_err());
+        let mut read = [0; 40];
+        assert!(block_on(memory.write_read(DEVICE, &[0; 2], &mut read)).is_err());
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Old {
+        a: u32,
+        b: bool,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct New {
+        a: u32,
+        b: bool,
+        c: u64,
+    }
+
+    fn write(buffer: &mut [u8], version: u16, old: &Old, name: [u8; 4]) -> usize {
+        let mut writer = SectionWriter::new(buffer, version).unwrap();
+        writer.write(old).unwrap();
+        writer.write(&name).unwrap();
+        writer.write(old).unwrap();
+        writer.finish()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut buffer = [0xff; 256];
+        let old = Old {
+            a: 0xdead_beef,
+            b: true,
+        };
+        write(&mut buffer, 7, &old, *b"wifi");
+
+        let mut reader = SectionReader::new(&buffer).unwrap();
+        assert_eq!(reader.version(), 7);
+        assert_eq!(
+            reader.read::<Old>(),
+            Some(Old {
+                a: 0xdead_beef,
+                b: true
+            })
+        );
+        assert_eq!(reader.read::<[u8; 4]>(), Some(*b"wifi"));
+        assert_eq!(
+            reader.read::<Old>(),
+            Some(Old {
+                a: 0xdead_beef,
+                b: true
+            })
+        );
+        // erased EEPROM after the last section
+        assert_eq!(reader.read::<Old>(), None);
+    }
+
+    #[test]
+    fn changed_section_resets_itself() {
+        let mut buffer = [0xff; 256];
+        write(&mut buffer, 1, &Old { a: 1, b: false }, *b"wifi");
+
+        let mut reader = SectionReader::new(&buffer).unwrap();
+        assert_eq!(reader.read::<New>(), None);
+        assert_eq!(reader.read::<[u8; 4]>(), Some(*b"wifi"));
+        assert_eq!(reader.read::<(u32, bool, u8)>(), None);
+    }
+
+    #[test]
+    fn corrupted_section_resets_itself() {
+        let mut buffer = [0xff; 256];
+        write(&mut buffer, 1, &Old { a: 1, b: false }, *b"wifi");
+        buffer[HEADER_LEN + LENGTH_LEN] ^= 0x01;
+
+        let mut reader = SectionReader::new(&buffer).unwrap();
+        assert_eq!(reader.read::<Old>(), None);
+        assert_eq!(reader.read::<[u8; 4]>(), Some(*b"wifi"));
+        assert_eq!(reader.read::<Old>(), Some(Old { a: 1, b: false }));
+    }
+
+    #[test]
+    fn broken_length_stops_reading() {
+        let mut buffer = [0xff; 256];
+        let len = write(&mut buffer, 1, &Old { a: 1, b: false }, *b"wifi");
+        buffer[HEADER_LEN] = 0x10;
+
+        let mut reader = SectionReader::new(&buffer[..len]).unwrap();
+        assert_eq!(reader.read::<Old>(), None);
+        assert_eq!(reader.read::<[u8; 4]>(), None);
+    }
+
+    #[test]
+    fn unversioned_config() {
+        // the old layout started with the crc framed mqtt config
+        let mut buffer = [0xff; 256];
+        postcard::to_slice_crc32(&Old { a: 1, b: false }, &mut buffer, CRC.digest()).unwrap();
+        assert!(SectionReader::new(&buffer).is_none());
+        assert!(SectionReader::new(&[0xff; 256]).is_none());
+    }
+
+    #[test]
+    fn legacy_config() {
+        // mqtt, wifi, stpm, calibration back to back as the old firmware
+        // wrote them, followed by erased EEPROM
+        let mut buffer = [0xff; 256];
+        let mut n = 0;
+        n += postcard::to_slice_crc32(&Old { a: 1883, b: true }, &mut buffer[n..], CRC.digest()).unwrap().len();
+        n += postcard::to_slice_crc32(b"wifi", &mut buffer[n..], CRC.digest()).unwrap().len();
+        n += postcard::to_slice_crc32(&(20usize, [3u8, 0]), &mut buffer[n..], CRC.digest()).unwrap().len();
+        postcard::to_slice_crc32(&(1.0f32, 1.0f32, [(1700.0f32, 0.005f32, 0u8); 2]), &mut buffer[n..], CRC.digest()).unwrap();
+        assert!(SectionReader::new(&buffer).is_none());
+
+        let mut reader = LegacyReader::new(&buffer);
+        assert_eq!(reader.read::<Old>(), Some(Old { a: 1883, b: true }));
+        assert_eq!(reader.read::<[u8; 4]>(), Some(*b"wifi"));
+        assert_eq!(reader.read::<(usize, [u8; 2])>(), Some((20, [3, 0])));
+        assert_eq!(
+            reader.read::<(f32, f32, [(f32, f32, u8); 2])>(),
+            Some((1.0, 1.0, [(1700.0, 0.005, 0); 2]))
+        );
+        assert_eq!(reader.read::<Old>(), None);
+    }
+
+    #[test]
+    fn legacy_config_stops_at_first_error() {
+        let mut buffer = [0xff; 256];
+        let n = postcard::to_slice_crc32(&Old { a: 1, b: false }, &mut buffer, CRC.digest()).unwrap().len();
+        postcard::to_slice_crc32(b"wifi", &mut buffer[n..], CRC.digest()).unwrap();
+        buffer[0] ^= 0x01;
+
+        let mut reader = LegacyReader::new(&buffer);
+        assert_eq!(reader.read::<Old>(), None);
+        assert_eq!(reader.read::<[u8; 4]>(), None);
+        // an erased EEPROM has no config at all
+        assert_eq!(LegacyReader::new(&[0xff; 256]).read::<Old>(), None);
+    }
+
+    #[test]
+    fn buffer_too_small() {
+        let mut buffer = [0u8; 16];
+        let mut writer = SectionWriter::new(&mut buffer, 1).unwrap();
+        assert_eq!(writer.write(&[0u32; 8]), None);
+        assert!(SectionWriter::new(&mut buffer[..2], 1).is_none());
+    }
+}
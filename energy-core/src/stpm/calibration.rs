@@ -0,0 +1,689 @@
+use serde::{Deserialize, Serialize};
+
+use crate::math::isqrt;
+
+use super::{energy::ENERGY_FRACTION_BITS, sample::{RawSampleApp, PERIOD_CLOCK_HZ, PHASE_CLOCK_HZ}};
+
+// these are settings of the chip that we don't change
+const VOLTAGE_REFERENCE: f32 = 1.18;
+const VOLTAGE_CALIBRATION: u16 = 0x800;
+const CURRENT_CALIBRATION: u16 = 0x800;
+
+pub const FIXED_DECIMALS_VOLTAGE: u32 = 11;
+pub const FIXED_DECIMALS_CURRENT: u32 = 16;
+pub const FIXED_DECIMALS_POWER: u32 = 20;
+pub const FIXED_DECIMALS_ENERGY: u32 = 22;
+pub const FIXED_DECIMALS_PHASE: u32 = 8;
+/// extra fractional bits of the voltage, current and power LSBs of
+/// `IntCalibration`, keeps the resolution with very sensitive current sensors
+const INT_LSB_BITS: u32 = 24;
+
+/// how the RMS values of a sample window are averaged
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+pub enum RmsMode {
+    /// mean of the RMS readings of the chip
+    #[default]
+    Mean,
+    /// square root of the mean of the squared readings, the RMS over the
+    /// whole window
+    True,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ConversionParameters {
+    // /// ADC reference voltage, 1.18 V by default
+    // pub voltage_reference: f32,
+    // /// analog gain factor selected in register DFE_CRx
+    // pub current_gain: StpmCurrentGain,
+
+    // /// 12 bit calibration factor corresponding to factors 0.75 to 1.0
+    // pub voltage_calibration: u16,
+    // /// 12 bit calibration factor corresponding to factors 0.75 to 1.0
+    // pub current_calibration: u16,
+    /// resistive divider factor for voltage measurement (1 + R2 / R1)
+    pub voltage_divider_factor: f32,
+    /// current sensor output in volts per amp, the resistance for a shunt
+    pub current_sensitivity: f32,
+    /// the digital integrator is enabled for a Rogowski coil
+    pub integrator: bool,
+    /// oscillator calibration factor (1.0 = 16MHz)
+    pub oscillator_factor: f32,
+    /// nominal line frequency in Hz, converts phase delays to angles
+    pub line_frequency: f32,
+    /// readings without load, in amps, watts and var
+    pub current_offset: f32,
+    pub active_power_offset: f32,
+    pub reactive_power_offset: f32,
+}
+
+impl Default for ConversionParameters {
+    fn default() -> Self {
+        Self {
+            // voltage_reference: 1.18,
+            // current_gain: StpmCurrentGain::X2,
+            // voltage_calibration: 0x800,
+            // current_calibration: 0x800,
+            voltage_divider_factor: 1700.0,
+            current_sensitivity: 0.005,
+            integrator: false,
+            oscillator_factor: 1.0,
+            line_frequency: 50.0,
+            current_offset: 0.0,
+            active_power_offset: 0.0,
+            reactive_power_offset: 0.0,
+        }
+    }
+}
+
+impl ConversionParameters {
+    pub fn to_float_cal(&self) -> FloatCalibration {
+        let cal_voltage = 0.75 + VOLTAGE_CALIBRATION as f32 * (0.25 / 0x1000 as f32);
+        let cal_current = 0.75 + CURRENT_CALIBRATION as f32 * (0.25 / 0x1000 as f32);
+
+        let gain_voltage = 2.0;
+        // the current gain is normalized to 16x in the stpm task
+        let gain_current = 16.0;
+
+        // decimation clock in Hz, independent of the line frequency setting
+        let dclk = 7812.5 * self.oscillator_factor;
+        // gain of the digital integrator at 50 Hz. it falls with 1 / f while
+        // the output of the coil rises with f, so the product is the same at
+        // any line frequency
+        let kint = if self.integrator { 0.8155 } else { 1.0 };
+
+        FloatCalibration {
+            voltage_rms_lsb: VOLTAGE_REFERENCE * self.voltage_divider_factor
+                / (cal_voltage * 2.0 * (1 << (15 - FIXED_DECIMALS_VOLTAGE)) as f32),
+            current_rms_lsb: VOLTAGE_REFERENCE
+                / (kint
+                    * self.current_sensitivity
+                    * cal_current
+                    * gain_current
+                    * (1 << (17 - FIXED_DECIMALS_CURRENT)) as f32),
+            power_lsb: VOLTAGE_REFERENCE * VOLTAGE_REFERENCE * self.voltage_divider_factor
+                / (kint
+                    * gain_voltage
+                    * gain_current
+                    * self.current_sensitivity
+                    * cal_voltage
+                    * cal_current
+                    * (1 << (28 - FIXED_DECIMALS_POWER)) as f32),
+            energy_lsb: VOLTAGE_REFERENCE
+                * VOLTAGE_REFERENCE
+                * self.voltage_divider_factor
+                * (1 << (FIXED_DECIMALS_ENERGY - 17)) as f32
+                / (dclk
+                    * kint
+                    * gain_voltage
+                    * gain_current
+                    * self.current_sensitivity
+                    * cal_voltage
+                    * cal_current
+                    * 3600.0), // convert Ws to Wh
+            phase_lsb: 360.0 * self.line_frequency
+                / (PHASE_CLOCK_HZ as f32 * self.oscillator_factor),
+            period_clock: PERIOD_CLOCK_HZ as f32 * self.oscillator_factor,
+            current_offset: self.current_offset,
+            active_power_offset: self.active_power_offset,
+            reactive_power_offset: self.reactive_power_offset,
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+#[derive(Clone, Copy, Debug)]
+pub struct FloatCalibration {
+    /// in volts
+    pub voltage_rms_lsb: f32,
+    /// in amperes
+    pub current_rms_lsb: f32,
+    /// in watts
+    pub power_lsb: f32,
+    /// in watt hours
+    pub energy_lsb: f32,
+    /// in degrees
+    pub phase_lsb: f32,
+    /// in Hz
+    pub period_clock: f32,
+    /// in amperes, watts and var
+    pub current_offset: f32,
+    pub active_power_offset: f32,
+    pub reactive_power_offset: f32,
+}
+
+impl FloatCalibration {
+    /// raw 15 bit voltage RMS value that corresponds to `volts`
+    pub fn voltage_to_raw(&self, volts: f32) -> u32 {
+        (volts * (1 << FIXED_DECIMALS_VOLTAGE) as f32 / self.voltage_rms_lsb) as u32
+    }
+
+    /// sag and swell thresholds are compared against the upper 10 bits of the
+    /// voltage RMS value
+    pub fn voltage_threshold(&self, volts: f32) -> u16 {
+        (self.voltage_to_raw(volts) >> 5).min(0x3ff) as u16
+    }
+
+    /// raw power value at gain 16 that corresponds to `watts`
+    pub fn power_to_raw(&self, watts: f32) -> i64 {
+        (watts * (1 << FIXED_DECIMALS_POWER) as f32 / self.power_lsb) as i64
+    }
+
+    /// raw 17 bit current RMS value at gain 16 that corresponds to `amps`
+    pub fn current_to_raw(&self, amps: f32) -> u32 {
+        (amps * (1 << FIXED_DECIMALS_CURRENT) as f32 / self.current_rms_lsb) as u32
+    }
+
+    /// the current swell threshold is compared against the upper 10 bits of
+    /// the current RMS value, which depends on the current gain of the chip
+    pub fn current_threshold(&self, amps: f32, anti_current_gain: i64) -> u16 {
+        ((self.current_to_raw(amps) / anti_current_gain as u32) >> 7).min(0x3ff) as u16
+    }
+
+    /// value of one energy register unit at gain 16 in mWh, with
+    /// ENERGY_FRACTION_BITS fractional bits
+    pub fn energy_lsb_fixed(&self) -> i64 {
+        (1e3 * self.energy_lsb as f64 * (1u64 << (ENERGY_FRACTION_BITS - FIXED_DECIMALS_ENERGY)) as f64) as i64
+    }
+
+    pub fn to_int_cal(&self) -> IntCalibration {
+        let extra = (1u64 << INT_LSB_BITS) as f64;
+        IntCalibration {
+            voltage_rms_lsb: (1e3 * self.voltage_rms_lsb as f64 * extra) as u64,
+            current_rms_lsb: (1e4 * self.current_rms_lsb as f64 * extra) as u64,
+            power_lsb: (1e3 * self.power_lsb as f64 * extra) as i64,
+            phase_lsb: (1e3 * self.phase_lsb * (1 << FIXED_DECIMALS_PHASE) as f32) as i32,
+            period_clock: (1e4 * self.period_clock) as u64,
+            current_offset: (1e4 * self.current_offset) as u64,
+            active_power_offset: (1e3 * self.active_power_offset) as i64,
+            reactive_power_offset: (1e3 * self.reactive_power_offset) as i64,
+        }
+    }
+}
+
+// impl FloatCalibration {
+//     pub fn apply(&self, sample: RawSampleChip, num_samples: usize) -> FloatCalibratedSample {
+//         let factor = 1.0 / num_samples as f32;
+//         FloatCalibratedSample {
+//             voltage_rms: sample.voltage_rms as f32 * self.voltage_rms_lsb * factor,
+//             current_rms: sample.current_rms as f32 * self.current_rms_lsb * factor,
+//             power_active: sample.power_active as f32 * self.power_lsb * factor,
+//             power_reactive: sample.power_reactive as f32 * self.power_lsb * factor,
+//             energy_active: sample.energy_active as f32 * self.energy_lsb,
+//         }
+//     }
+// }
+
+// #[derive(Clone, Copy, Debug)]
+// pub struct FloatCalibratedSample {
+//     pub voltage_rms: f32,
+//     pub current_rms: f32,
+//     pub power_active: f32,
+//     pub power_reactive: f32,
+//     pub energy_active: f32,
+// }
+
+// -----------------------------------------------------------------------------
+
+#[derive(Clone, Copy, Debug)]
+pub struct IntCalibration {
+    /// in volts, with INT_LSB_BITS extra fractional bits
+    pub voltage_rms_lsb: u64,
+    /// in amperes, with INT_LSB_BITS extra fractional bits
+    pub current_rms_lsb: u64,
+    /// in watts, with INT_LSB_BITS extra fractional bits
+    pub power_lsb: i64,
+    /// in degrees
+    pub phase_lsb: i32,
+    /// in Hz
+    pub period_clock: u64,
+    /// 4 decimal places
+    pub current_offset: u64,
+    /// 3 decimal places, also applies to the fundamental power
+    pub active_power_offset: i64,
+    pub reactive_power_offset: i64,
+}
+
+impl IntCalibration {
+    /// converts a single raw voltage RMS reading
+    pub fn voltage(&self, voltage_rms: u64) -> Option<u64> {
+        scale_unsigned(voltage_rms as u128, self.voltage_rms_lsb, 1, FIXED_DECIMALS_VOLTAGE + INT_LSB_BITS)
+    }
+
+    /// converts a single raw current RMS reading
+    pub fn current(&self, current_rms: u64) -> Option<u64> {
+        self.remove_current_offset(scale_unsigned(current_rms as u128, self.current_rms_lsb, 1, FIXED_DECIMALS_CURRENT + INT_LSB_BITS)?)
+    }
+
+    /// converts a single raw active power reading, a zero reading was zeroed
+    /// by the no-load detection and stays zero
+    pub fn power(&self, power: i64) -> Option<i64> {
+        let offset = if power == 0 { 0 } else { self.active_power_offset };
+        scale_signed(power, self.power_lsb, 1, FIXED_DECIMALS_POWER + INT_LSB_BITS)?.checked_sub(offset)
+    }
+
+    /// the noise adds to the current in quadrature, 4 decimal places in and out
+    fn remove_current_offset(&self, current: u64) -> Option<u64> {
+        if self.current_offset == 0 {
+            return Some(current);
+        }
+        let square = (current as u128 * current as u128).saturating_sub(self.current_offset as u128 * self.current_offset as u128);
+        Some(isqrt(u64::try_from(square).ok()?))
+    }
+
+    /// line frequency from the sum of `count` periods, None without voltage
+    /// or if `is_plausible` rejects it
+    pub fn frequency(&self, period: u64, count: usize, is_plausible: impl Fn(u64) -> bool) -> Option<u64> {
+        if period == 0 {
+            return None;
+        }
+        Some(self.period_clock.checked_mul(count as u64)? / period).filter(|f| is_plausible(*f))
+    }
+
+    /// every value that can not be computed (empty window or out of range)
+    /// is None. `is_plausible` checks the frequency (4 decimal places)
+    /// against the nominal line frequency.
+    pub fn apply(&self, sample: RawSampleApp, is_plausible: impl Fn(u64) -> bool) -> IntCalibratedSample {
+        let n = sample.num_samples;
+        // the mapped powers of the window are incomplete without the own voltage
+        let power_samples = if sample.mapping_failed { 0 } else { n };
+        let power = |sum: i64| scale_signed(sum, self.power_lsb, power_samples, FIXED_DECIMALS_POWER + INT_LSB_BITS);
+
+        // the no-load offset of the active power is at the line frequency, so
+        // the fundamental power has it as well. the apparent power keeps its
+        // noise floor. readings zeroed by the no-load detection have no
+        // offset to remove.
+        let active_offset = window_offset(self.active_power_offset, sample.no_load_active, n);
+        let fundamental_offset = window_offset(self.active_power_offset, sample.no_load_current, n);
+        let reactive_offset = window_offset(self.reactive_power_offset, sample.no_load_reactive, n);
+        let power_active = power(sample.power_active).and_then(|p| p.checked_sub(active_offset?));
+        let power_fundamental = power(sample.power_fundamental).and_then(|p| p.checked_sub(fundamental_offset?));
+        let power_apparent = power(sample.power_apparent);
+
+        IntCalibratedSample {
+            // voltage: ignore current gain
+            voltage_rms: window_rms(sample.voltage_rms, sample.voltage_rms_sq, n, sample.rms_mode)
+                .and_then(|rms| scale_unsigned(rms as u128, self.voltage_rms_lsb, 1, FIXED_DECIMALS_VOLTAGE + INT_LSB_BITS + RMS_EXTRA_BITS)),
+            // min / max: single samples
+            voltage_rms_min: self.voltage(sample.voltage_rms_range.min),
+            voltage_rms_max: self.voltage(sample.voltage_rms_range.max),
+            current_rms_min: self.current(sample.current_rms_range.min),
+            current_rms_max: self.current(sample.current_rms_range.max),
+            power_active_min: self.power(sample.power_active_range.min).filter(|_| !sample.mapping_failed),
+            power_active_max: self.power(sample.power_active_range.max).filter(|_| !sample.mapping_failed),
+            // current and power: use num_samples and current gain
+            current_rms: window_rms(sample.current_rms, sample.current_rms_sq, n, sample.rms_mode)
+                .and_then(|rms| scale_unsigned(rms as u128, self.current_rms_lsb, 1, FIXED_DECIMALS_CURRENT + INT_LSB_BITS + RMS_EXTRA_BITS))
+                .and_then(|current| self.remove_current_offset(current)),
+            power_active,
+            power_reactive: power(sample.power_reactive).and_then(|p| p.checked_sub(reactive_offset?)),
+            power_apparent,
+            power_fundamental,
+            // harmonic power: everything that is not at the line frequency,
+            // the offsets cancel
+            power_harmonic: power_active.zip(power_fundamental).and_then(|(active, fundamental)| active.checked_sub(fundamental)),
+            // power factor: with the corrected active power, sign follows
+            // active power
+            power_factor: match (power_active, power_apparent) {
+                (Some(active), Some(apparent)) if apparent != 0 => {
+                    Some((active as i128 * 1000 / apparent as i128).clamp(-1000, 1000) as i64)
+                }
+                _ => None,
+            },
+            phase_angle: scale_signed(sample.phase_angle, self.phase_lsb as i64, n, FIXED_DECIMALS_PHASE),
+            frequency: self.frequency(sample.period, sample.period_samples, is_plausible),
+            // energy: already calibrated by the stpm task, without the
+            // offsets. the no-load threshold keeps the noise out of the totals.
+            energy_active: sample.energy_active,
+            energy_fundamental: sample.energy_fundamental,
+            energy_reactive: sample.energy_reactive,
+            energy_apparent: sample.energy_apparent,
+            energy_import: sample.energy_import,
+            energy_export: sample.energy_export,
+            energy_combined_import: sample.energy_combined_import,
+            energy_combined_export: sample.energy_combined_export,
+            energy_gap: sample.energy_gap,
+            current_swell: sample.current_swell,
+            current_gain: sample.current_gain.factor(),
+        }
+    }
+}
+
+/// offset of the average of a window in which `zeroed` of `num_samples`
+/// readings were zeroed by the no-load detection
+fn window_offset(offset: i64, zeroed: usize, num_samples: usize) -> Option<i64> {
+    let loaded = num_samples.checked_sub(zeroed)?;
+    i64::try_from(offset as i128 * loaded as i128 / num_samples.max(1) as i128).ok()
+}
+
+/// `sum * lsb / num_samples / 2^decimals` with a 128 bit intermediate, None
+/// for an empty window or if the result does not fit
+fn scale_signed(sum: i64, lsb: i64, num_samples: usize, decimals: u32) -> Option<i64> {
+    if num_samples == 0 {
+        return None;
+    }
+    let value = sum as i128 * lsb as i128 / num_samples as i128 / (1i128 << decimals);
+    i64::try_from(value).ok()
+}
+
+/// unsigned version of `scale_signed`
+fn scale_unsigned(sum: u128, lsb: u64, num_samples: usize, decimals: u32) -> Option<u64> {
+    if num_samples == 0 {
+        return None;
+    }
+    let value = sum.checked_mul(lsb as u128)? / num_samples as u128 / (1u128 << decimals);
+    u64::try_from(value).ok()
+}
+
+/// fractional bits of `window_rms`, the square root would otherwise lose the
+/// resolution gained by averaging
+const RMS_EXTRA_BITS: u32 = 8;
+
+/// RMS value of a window from the sum and the sum of squares of the single
+/// readings, with RMS_EXTRA_BITS fractional bits. None for an empty window
+/// or if the result does not fit.
+fn window_rms(sum: u64, sum_sq: u128, num_samples: usize, mode: RmsMode) -> Option<u64> {
+    if num_samples == 0 {
+        return None;
+    }
+    match mode {
+        RmsMode::Mean => u64::try_from(((sum as u128) << RMS_EXTRA_BITS) / num_samples as u128).ok(),
+        RmsMode::True => {
+            let mean_sq = sum_sq.checked_mul(1 << (2 * RMS_EXTRA_BITS))? / num_samples as u128;
+            Some(isqrt(u64::try_from(mean_sq).ok()?))
+        }
+    }
+}
+
+/// None where the value could not be computed, see `IntCalibration::apply`
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct IntCalibratedSample {
+    pub voltage_rms: Option<u64>,    // 3 decimal places
+    pub current_rms: Option<u64>,    // 4 decimal places
+    pub power_active: Option<i64>,   // 3 decimal places
+    pub voltage_rms_min: Option<u64>, // 3 decimal places
+    pub voltage_rms_max: Option<u64>, // 3 decimal places
+    pub current_rms_min: Option<u64>, // 4 decimal places
+    pub current_rms_max: Option<u64>, // 4 decimal places
+    pub power_active_min: Option<i64>, // 3 decimal places
+    pub power_active_max: Option<i64>, // 3 decimal places
+    pub power_reactive: Option<i64>, // 3 decimal places
+    pub power_apparent: Option<i64>, // 3 decimal places
+    pub power_factor: Option<i64>,   // 3 decimal places
+    pub power_fundamental: Option<i64>, // 3 decimal places
+    pub power_harmonic: Option<i64>, // 3 decimal places
+    pub phase_angle: Option<i64>,    // 3 decimal places
+    pub frequency: Option<u64>, // 4 decimal places
+    pub energy_active: i64,  // 3 decimal places
+    pub energy_fundamental: i64, // 3 decimal places
+    pub energy_reactive: i64, // 3 decimal places
+    pub energy_apparent: i64, // 3 decimal places
+    pub energy_import: i64,  // 3 decimal places
+    pub energy_export: i64,  // 3 decimal places
+    pub energy_combined_import: i64, // 3 decimal places
+    pub energy_combined_export: i64, // 3 decimal places
+    pub energy_gap: bool,
+    pub current_swell: bool,
+    pub current_gain: u8,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stpm::{chip::StpmCurrentGain, sample::MinMax};
+
+    /// 50.0000 Hz accepted, everything else rejected
+    fn fifty_hz(frequency: u64) -> bool {
+        frequency.abs_diff(50_0000) < 1000
+    }
+
+    fn int_cal(current_sensitivity: f32) -> IntCalibration {
+        ConversionParameters {
+            current_sensitivity,
+            ..Default::default()
+        }
+        .to_float_cal()
+        .to_int_cal()
+    }
+
+    /// `n` identical readings
+    fn window(n: usize, voltage: u64, current: u64, power: i64, gain: StpmCurrentGain) -> RawSampleApp {
+        RawSampleApp {
+            voltage_rms: voltage * n as u64,
+            current_rms: current * n as u64,
+            voltage_rms_sq: (voltage * voltage) as u128 * n as u128,
+            current_rms_sq: (current * current) as u128 * n as u128,
+            power_active: power * n as i64,
+            power_reactive: -power * n as i64,
+            power_apparent: power.abs() * n as i64,
+            power_fundamental: power * n as i64,
+            voltage_rms_range: MinMax { min: voltage, max: voltage },
+            current_rms_range: MinMax { min: current, max: current },
+            power_active_range: MinMax { min: power, max: power },
+            current_gain: gain,
+            num_samples: n,
+            ..Default::default()
+        }
+    }
+
+    fn assert_close(value: Option<i64>, expected: f64) {
+        let value = value.unwrap() as f64;
+        assert!((value - expected).abs() <= expected.abs() * 1e-4 + 1.0, "{value} != {expected}");
+    }
+
+    #[test]
+    fn empty_window() {
+        let mut sample = window(0, 10_000, 20_000, 30_000, StpmCurrentGain::X2);
+        sample.energy_active = 1234;
+        sample.period = 0;
+        let result = int_cal(0.005).apply(sample, fifty_hz);
+
+        assert!(result.voltage_rms.is_none());
+        assert!(result.current_rms.is_none());
+        assert!(result.power_active.is_none());
+        assert!(result.power_reactive.is_none());
+        assert!(result.power_apparent.is_none());
+        assert!(result.power_harmonic.is_none());
+        assert!(result.phase_angle.is_none());
+        assert!(result.frequency.is_none());
+        assert!(result.power_factor.is_none());
+        // single readings and totals don't depend on the window
+        assert!(result.voltage_rms_min.is_some());
+        assert!(result.power_active_max.is_some());
+        assert_eq!(result.energy_active, 1234);
+    }
+
+    #[test]
+    fn huge_window() {
+        let cal = int_cal(0.005);
+        for rms_mode in [RmsMode::Mean, RmsMode::True] {
+            let sample = RawSampleApp {
+                voltage_rms: u64::MAX,
+                current_rms: u64::MAX,
+                voltage_rms_sq: u128::MAX,
+                current_rms_sq: u128::MAX,
+                rms_mode,
+                power_active: i64::MIN,
+                power_reactive: i64::MAX,
+                power_apparent: i64::MAX,
+                power_fundamental: i64::MAX,
+                phase_angle: i64::MIN,
+                period: u64::MAX,
+                period_samples: usize::MAX,
+                num_samples: usize::MAX - 1,
+                ..Default::default()
+            };
+            // must not panic, the sums are far beyond anything the chip delivers
+            let result = cal.apply(sample, fifty_hz);
+            assert!(result.frequency.is_none());
+            // the averaged powers round to zero
+            assert_eq!(result.power_active, Some(0));
+            assert_eq!(result.power_factor, None);
+        }
+
+        // the frequency of a huge number of periods overflows
+        assert_eq!(cal.frequency(u64::MAX, usize::MAX, fifty_hz), None);
+        assert_eq!(cal.frequency(2500 * 20, 20, fifty_hz), Some(50_0000));
+        assert_eq!(cal.frequency(2500 * 20, 20, |_| false), None);
+    }
+
+    #[test]
+    fn current_gain_is_normalized() {
+        let cal = int_cal(0.005);
+        // the same current read at gain 2 and 16, normalized to gain 16
+        let x2 = cal.apply(window(20, 10_000, 0x1000 * StpmCurrentGain::X2.anti_gain() as u64, 0x10000, StpmCurrentGain::X2), fifty_hz);
+        let x16 = cal.apply(window(20, 10_000, 0x1000, 0x10000 / 8, StpmCurrentGain::X16), fifty_hz);
+        assert_eq!(x2.current_gain, 2);
+        assert_eq!(x16.current_gain, 16);
+        // the gain 16 reading has 3 bits less resolution
+        assert!(x2.current_rms.unwrap().abs_diff(x16.current_rms.unwrap() * 8) <= 8);
+        assert!(x2.power_active.unwrap().abs_diff(x16.power_active.unwrap() * 8) <= 8);
+
+        // the chip compares the current swell threshold with the raw value
+        let float_cal = ConversionParameters::default().to_float_cal();
+        let x16 = float_cal.current_threshold(10.0, StpmCurrentGain::X16.anti_gain()) as i64;
+        let x2 = float_cal.current_threshold(10.0, StpmCurrentGain::X2.anti_gain()) as i64;
+        assert!((x2 - x16 / 8).abs() <= 1);
+    }
+
+    #[test]
+    fn offsets() {
+        let offsets = ConversionParameters {
+            current_offset: 0.05,
+            active_power_offset: 2.5,
+            reactive_power_offset: -1.25,
+            ..Default::default()
+        }
+        .to_float_cal()
+        .to_int_cal();
+        let plain = int_cal(0.005);
+        let mut sample = window(20, 10_000, 0x1000, 0x8000, StpmCurrentGain::X16);
+        sample.power_fundamental = sample.power_active * 9 / 10;
+        sample.energy_active = 1234;
+
+        let base = plain.apply(sample, fifty_hz);
+        let result = offsets.apply(sample, fifty_hz);
+
+        // the current noise adds in quadrature
+        let current = base.current_rms.unwrap();
+        assert_eq!(result.current_rms, Some(isqrt(current * current - 500 * 500)));
+        assert_eq!(offsets.current(0x1000), Some(isqrt(plain.current(0x1000).unwrap().pow(2) - 500 * 500)));
+        // below the offset there is no current
+        assert_eq!(offsets.current(10), Some(0));
+
+        assert_eq!(result.power_active, base.power_active.map(|p| p - 2500));
+        assert_eq!(result.power_active_min, base.power_active_min.map(|p| p - 2500));
+        assert_eq!(offsets.power(0x8000), plain.power(0x8000).map(|p| p - 2500));
+        assert_eq!(result.power_reactive, base.power_reactive.map(|p| p + 1250));
+        assert_eq!(result.power_fundamental, base.power_fundamental.map(|p| p - 2500));
+        assert_eq!(result.power_harmonic, base.power_harmonic);
+        assert_eq!(result.power_apparent, base.power_apparent);
+        let expected_pf = result.power_active.unwrap() * 1000 / result.power_apparent.unwrap();
+        assert_eq!(result.power_factor, Some(expected_pf));
+        assert!(result.power_factor < base.power_factor);
+        assert_eq!(result.energy_active, 1234);
+    }
+
+    #[test]
+    fn offsets_with_no_load() {
+        let offsets = ConversionParameters {
+            active_power_offset: 2.5,
+            reactive_power_offset: -1.25,
+            ..Default::default()
+        }
+        .to_float_cal()
+        .to_int_cal();
+        let plain = int_cal(0.005);
+
+        // every reading of the idle channel was zeroed, nothing is left to
+        // correct
+        let mut idle = window(20, 10_000, 0, 0, StpmCurrentGain::X16);
+        idle.no_load_active = 20;
+        idle.no_load_reactive = 20;
+        idle.no_load_current = 20;
+        let result = offsets.apply(idle, fifty_hz);
+        assert_eq!(result.power_active, Some(0));
+        assert_eq!(result.power_reactive, Some(0));
+        assert_eq!(result.power_fundamental, Some(0));
+        assert_eq!(result.power_harmonic, Some(0));
+        assert_eq!((result.power_active_min, result.power_active_max), (Some(0), Some(0)));
+        assert_eq!(result.power_factor, None);
+
+        // half of the active power readings were zeroed, only the other half
+        // carries the offset. the reactive power was above its threshold.
+        let mut half = window(20, 10_000, 0x1000, 0x8000, StpmCurrentGain::X16);
+        half.power_active /= 2;
+        half.power_active_range.min = 0;
+        half.no_load_active = 10;
+        let base = plain.apply(half, fifty_hz);
+        let result = offsets.apply(half, fifty_hz);
+        assert_eq!(result.power_active, base.power_active.map(|p| p - 1250));
+        assert_eq!(result.power_active_min, Some(0));
+        assert_eq!(result.power_active_max, base.power_active_max.map(|p| p - 2500));
+        assert_eq!(result.power_fundamental, base.power_fundamental.map(|p| p - 2500));
+        assert_eq!(result.power_reactive, base.power_reactive.map(|p| p + 1250));
+        let expected_pf = result.power_active.unwrap() * 1000 / result.power_apparent.unwrap();
+        assert_eq!(result.power_factor, Some(expected_pf));
+    }
+
+    #[test]
+    fn offset_without_load() {
+        let offsets = ConversionParameters {
+            active_power_offset: 2.5,
+            ..Default::default()
+        }
+        .to_float_cal();
+        // exactly the offset is measured
+        let raw = offsets.power_to_raw(2.5);
+        let cal = offsets.to_int_cal();
+        let result = cal.apply(window(20, 10_000, 0x100, raw, StpmCurrentGain::X16), fifty_hz);
+        assert!(result.power_active.unwrap().abs() <= 1);
+        assert!(result.power_factor.unwrap().abs() <= 1);
+        assert!(result.power_harmonic.unwrap().abs() <= 1);
+    }
+
+    #[test]
+    fn failed_mapping_drops_the_powers() {
+        let cal = int_cal(0.005);
+        let mut sample = window(20, 10_000, 0x1000, 0x8000, StpmCurrentGain::X16);
+        sample.mapping_failed = true;
+        sample.energy_active = 1234;
+        let result = cal.apply(sample, fifty_hz);
+        assert!(result.voltage_rms.is_some());
+        assert!(result.current_rms.is_some());
+        assert_eq!(result.power_active, None);
+        assert_eq!(result.power_active_min, None);
+        assert_eq!(result.power_apparent, None);
+        assert_eq!(result.power_factor, None);
+        assert_eq!(result.power_harmonic, None);
+        assert_eq!(result.energy_active, 1234);
+    }
+
+    #[test]
+    fn extreme_current_sensors() {
+        // 0.1 mOhm shunt, 5 mOhm shunt, 10 kOhm burden with 2000 and 1 turns
+        for sensitivity in [0.0001, 0.005, 10_000.0 / 2000.0, 10_000.0] {
+            let float_cal = ConversionParameters {
+                current_sensitivity: sensitivity,
+                ..Default::default()
+            }
+            .to_float_cal();
+            let cal = float_cal.to_int_cal();
+            let (voltage, current, power) = (10_000u64, 0x8000u64, 1_000_000i64);
+            let result = cal.apply(window(20, voltage, current, power, StpmCurrentGain::X16), fifty_hz);
+
+            let voltage_f = voltage as f64 * float_cal.voltage_rms_lsb as f64 / (1 << FIXED_DECIMALS_VOLTAGE) as f64;
+            let current_f = current as f64 * float_cal.current_rms_lsb as f64 / (1 << FIXED_DECIMALS_CURRENT) as f64;
+            let power_f = power as f64 * float_cal.power_lsb as f64 / (1 << FIXED_DECIMALS_POWER) as f64;
+            assert_close(result.voltage_rms.map(|v| v as i64), voltage_f * 1e3);
+            assert_close(result.current_rms.map(|c| c as i64), current_f * 1e4);
+            assert_close(result.power_active, power_f * 1e3);
+            assert_close(cal.current(current).map(|c| c as i64), current_f * 1e4);
+            assert_close(cal.power(power), power_f * 1e3);
+
+            let energy_f = 1e3 * float_cal.energy_lsb as f64 * (1u64 << (ENERGY_FRACTION_BITS - FIXED_DECIMALS_ENERGY)) as f64;
+            assert!(float_cal.energy_lsb_fixed() > 0);
+            assert_close(Some(float_cal.energy_lsb_fixed()), energy_f);
+        }
+    }
+}
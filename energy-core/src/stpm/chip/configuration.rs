@@ -0,0 +1,233 @@
+use serde::{Deserialize, Serialize};
+
+use super::events::*;
+use crate::stpm::driver::uart::STPM_UART_BAUD;
+
+
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub enum StpmCurrentGain {
+    #[default]
+    X2 = 0,
+    X4 = 1,
+    X8 = 2,
+    X16 = 3,
+}
+
+impl StpmCurrentGain {
+    /// factor to normalize raw current values to gain 16
+    pub fn anti_gain(&self) -> i64 {
+        match self {
+            StpmCurrentGain::X2 => 8,
+            StpmCurrentGain::X4 => 4,
+            StpmCurrentGain::X8 => 2,
+            StpmCurrentGain::X16 => 1,
+        }
+    }
+
+    /// the amplification, 2 to 16
+    pub fn factor(&self) -> u8 {
+        16 / self.anti_gain() as u8
+    }
+
+    pub fn higher(&self) -> Option<Self> {
+        match self {
+            StpmCurrentGain::X2 => Some(StpmCurrentGain::X4),
+            StpmCurrentGain::X4 => Some(StpmCurrentGain::X8),
+            StpmCurrentGain::X8 => Some(StpmCurrentGain::X16),
+            StpmCurrentGain::X16 => None,
+        }
+    }
+
+    pub fn lower(&self) -> Option<Self> {
+        match self {
+            StpmCurrentGain::X2 => None,
+            StpmCurrentGain::X4 => Some(StpmCurrentGain::X2),
+            StpmCurrentGain::X8 => Some(StpmCurrentGain::X4),
+            StpmCurrentGain::X16 => Some(StpmCurrentGain::X8),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub enum StpmLineFrequency {
+    #[default]
+    F50 = 0,
+    F60 = 1,
+}
+
+impl StpmLineFrequency {
+    pub fn hz(&self) -> u32 {
+        match self {
+            StpmLineFrequency::F50 => 50,
+            StpmLineFrequency::F60 => 60,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct StpmChannelConfiguration {
+    pub current_gain: StpmCurrentGain,
+    pub voltage_phase_comp: u8,
+    pub current_phase_comp: u16,
+    pub voltage_calibration: u16,
+    pub voltage_swell_threshold: u16,
+    pub voltage_sag_threshold: u16,
+    pub current_calibration: u16,
+    pub current_swell_threshold: u16,
+    /// digital integrator for Rogowski coils
+    pub integrator: bool,
+}
+
+impl Default for StpmChannelConfiguration {
+    fn default() -> Self {
+        Self {
+            current_gain: Default::default(),
+            voltage_phase_comp: Default::default(),
+            current_phase_comp: Default::default(),
+            voltage_calibration: 0x800,
+            current_calibration: 0x800,
+            // To disable sag detection, the SAG_THRx register must be set to zero.
+            voltage_sag_threshold: 0x0,
+            // To disable swell detection, the registers SWV_THRx and SWC_THRx must have maximum value 0x3FF.
+            voltage_swell_threshold: 0x3ff,
+            current_swell_threshold: 0x3ff,
+            integrator: false,
+        }
+    }
+}
+
+/// ROC1 / ROC2 in DSP_CR1 / DSP_CR2, enables the digital integrator
+pub const DSP_CR_ROC: u32 = 1 << 21;
+
+/// one step of the current phase compensation in DSP_CR4, in microseconds
+pub const CURRENT_PHASE_COMP_LSB_US: f32 = 0.25;
+pub const CURRENT_PHASE_COMP_MAX: u16 = 0x3ff;
+/// one step of the voltage phase compensation in DSP_CR4, in microseconds
+pub const VOLTAGE_PHASE_COMP_LSB_US: f32 = 8.0;
+pub const VOLTAGE_PHASE_COMP_MAX: u8 = 0x3;
+
+impl StpmChannelConfiguration {
+    /// value of DFE_CR1 / DFE_CR2, enables the channels and sets the gain
+    pub fn dfe_cr(&self) -> u32 {
+        0x03270327 | (self.current_gain as u32) << 26
+    }
+
+    /// value of DSP_CR1 / DSP_CR2, `base` holds the bits that differ between
+    /// the channels
+    pub fn dsp_cr_control(&self, base: u32) -> u32 {
+        if self.integrator {
+            base | DSP_CR_ROC
+        } else {
+            base
+        }
+    }
+
+    /// value of DSP_CR6 / DSP_CR8
+    pub fn dsp_cr_current(&self) -> u32 {
+        let mut value = 0;
+        value |= self.current_calibration as u32 & 0xFFF;
+        value |= (self.current_swell_threshold as u32 & 0x3FF) << 12;
+        value
+    }
+
+    /// range of `set_phase_compensation`
+    pub const PHASE_COMP_MIN_US: f32 = -(VOLTAGE_PHASE_COMP_MAX as f32 * VOLTAGE_PHASE_COMP_LSB_US);
+    pub const PHASE_COMP_MAX_US: f32 = CURRENT_PHASE_COMP_MAX as f32 * CURRENT_PHASE_COMP_LSB_US;
+
+    /// positive values delay the current, for sensors where the current leads.
+    /// negative values delay the voltage, which only has coarse steps, so the
+    /// current is delayed by the remainder.
+    pub fn set_phase_compensation(&mut self, micros: f32) {
+        let micros = micros.clamp(Self::PHASE_COMP_MIN_US, Self::PHASE_COMP_MAX_US);
+
+        let voltage_steps = if micros < 0.0 {
+            // round up: -micros / LSB, without f32::ceil in core
+            let steps = (-micros / VOLTAGE_PHASE_COMP_LSB_US) as u8;
+            if steps as f32 * VOLTAGE_PHASE_COMP_LSB_US < -micros {
+                steps + 1
+            } else {
+                steps
+            }
+        } else {
+            0
+        };
+        let current_micros = micros + voltage_steps as f32 * VOLTAGE_PHASE_COMP_LSB_US;
+
+        self.voltage_phase_comp = voltage_steps.min(VOLTAGE_PHASE_COMP_MAX);
+        self.current_phase_comp =
+            ((current_micros / CURRENT_PHASE_COMP_LSB_US + 0.5) as u16).min(CURRENT_PHASE_COMP_MAX);
+    }
+}
+
+/// events that pull the INT pin low, the same for both channels
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct StpmInterrupts {
+    pub voltage_sag: bool,
+    pub voltage_swell: bool,
+    pub current_swell: bool,
+    pub sign_change: bool,
+    pub overflow: bool,
+    pub crc_error: bool,
+}
+
+impl StpmInterrupts {
+    /// mask for DSP_IRQ1 / DSP_IRQ2
+    pub fn dsp_mask(&self) -> u32 {
+        let mut mask = 0;
+        if self.voltage_sag {
+            mask |= SR_VOLTAGE_SAG_START | SR_VOLTAGE_SAG_END;
+        }
+        if self.voltage_swell {
+            mask |= SR_VOLTAGE_SWELL_START | SR_VOLTAGE_SWELL_END;
+        }
+        if self.current_swell {
+            mask |= SR_CURRENT_SWELL_START | SR_CURRENT_SWELL_END;
+        }
+        if self.sign_change {
+            mask |= SR_ACTIVE_POWER_SIGN | SR_REACTIVE_POWER_SIGN;
+        }
+        if self.overflow {
+            mask |= SR_ACTIVE_ENERGY_OVERFLOW | SR_REACTIVE_ENERGY_OVERFLOW;
+        }
+        mask
+    }
+
+    /// mask for the LSW of US_REG3
+    pub fn us_mask(&self) -> u16 {
+        if self.crc_error {
+            US_CRC_ERROR
+        } else {
+            0
+        }
+    }
+
+    pub fn any(&self) -> bool {
+        self.dsp_mask() != 0 || self.us_mask() != 0
+    }
+
+    /// sag and swell events are completely covered by interrupts, no need to
+    /// poll the live event registers
+    pub fn covers_events(&self) -> bool {
+        self.voltage_sag && self.voltage_swell && self.current_swell
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct StpmConfiguration {
+    pub line_frequency: StpmLineFrequency,
+    pub channels: [StpmChannelConfiguration; 2],
+    pub interrupts: StpmInterrupts,
+    /// UART baud rate, ignored when using SPI
+    pub baud_rate: u32,
+}
+
+impl Default for StpmConfiguration {
+    fn default() -> Self {
+        Self {
+            line_frequency: Default::default(),
+            channels: Default::default(),
+            interrupts: Default::default(),
+            baud_rate: STPM_UART_BAUD,
+        }
+    }
+}
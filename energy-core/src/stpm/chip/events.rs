@@ -0,0 +1,50 @@
+// bits of the live event registers, DSP_EV1 for channel 1 and DSP_EV2 for
+// channel 2. they stay set as long as the condition is present.
+
+/// current RMS is above the swell threshold
+pub const EV_CURRENT_SWELL: u32 = 1 << 14;
+/// voltage RMS is below the sag threshold
+pub const EV_VOLTAGE_SAG: u32 = 1 << 19;
+/// voltage RMS is above the swell threshold
+pub const EV_VOLTAGE_SWELL: u32 = 1 << 21;
+
+// bits of the status registers DSP_SR1 / DSP_SR2, the interrupt mask registers
+// DSP_IRQ1 / DSP_IRQ2 use the same layout. status bits are latched until they
+// are cleared by writing a 1.
+
+/// sign of the active power changed
+pub const SR_ACTIVE_POWER_SIGN: u32 = 1 << 4;
+/// sign of the reactive power changed
+pub const SR_REACTIVE_POWER_SIGN: u32 = 1 << 6;
+/// active energy register overflowed
+pub const SR_ACTIVE_ENERGY_OVERFLOW: u32 = 1 << 8;
+/// reactive energy register overflowed
+pub const SR_REACTIVE_ENERGY_OVERFLOW: u32 = 1 << 10;
+pub const SR_CURRENT_SWELL_START: u32 = 1 << 14;
+pub const SR_CURRENT_SWELL_END: u32 = 1 << 15;
+pub const SR_VOLTAGE_SAG_START: u32 = 1 << 19;
+pub const SR_VOLTAGE_SAG_END: u32 = 1 << 20;
+pub const SR_VOLTAGE_SWELL_START: u32 = 1 << 21;
+pub const SR_VOLTAGE_SWELL_END: u32 = 1 << 22;
+
+// US_REG3 holds the communication interrupt mask in the LSW and the matching
+// status bits in the MSW
+
+/// CRC error on a received frame
+pub const US_CRC_ERROR: u16 = 1 << 1;
+
+// the time registers DSP_REG16 (channel 1) and DSP_REG18 (channel 2) hold the
+// duration of the current / last sag event in bits 0-14 and the duration of the
+// current / last swell event in bits 16-30.
+// DSP_REG17 and DSP_REG19 hold the current swell time in bits 16-30.
+
+/// time counters count in steps of 64 decimation clock periods (7812.5 Hz)
+pub const EVENT_TIME_LSB_US: u64 = 8192;
+
+pub fn sag_time(reg: u32) -> u32 {
+    reg & 0x7fff
+}
+
+pub fn swell_time(reg: u32) -> u32 {
+    (reg >> 16) & 0x7fff
+}
@@ -7,8 +7,19 @@ pub use reader::Reader;
 mod configuration;
 pub use configuration::*;
 
+mod events;
+pub use events::*;
+
 use super::driver::StpmDriver;
 
+#[derive(Copy, Clone, Debug, Default)]
+pub struct StpmStatus {
+    /// DSP_SR1 / DSP_SR2
+    pub dsp: [u32; 2],
+    /// status half of US_REG3
+    pub us: u16,
+}
+
 pub struct Stpm<'a, D: StpmDriver> {
     pub driver: &'a mut D,
 }
@@ -19,7 +30,6 @@ impl<'a, D: StpmDriver> Stpm<'a, D> {
     }
 
     /// uses two driver transactions to read a register. for more efficient read access, use `read_registers()`
-    #[allow(unused)]
     pub async fn read_register(&mut self, reg: Reg) -> Result<u32, D::Error> {
         self.driver.transaction(Some(reg.addr()), None).await?;
         self.driver.transaction(None, None).await
@@ -43,9 +53,51 @@ impl<'a, D: StpmDriver> Stpm<'a, D> {
         self.write_register_16_msw(reg, (value >> 16) as u16).await
     }
 
+    /// reads the DSP status registers and the communication status, then clears
+    /// all bits that were set. bits set in between are kept for the next call.
+    pub async fn read_clear_status(&mut self) -> Result<StpmStatus, D::Error> {
+        let (mut sr1, mut sr2, mut us_reg3) = (0, 0, 0);
+
+        Reader::create(self)
+            .read_u32(Reg::DSP_SR1, &mut sr1).await?
+            .read_u32(Reg::DSP_SR2, &mut sr2).await?
+            .read_u32(Reg::US_REG3, &mut us_reg3).await?
+            .end().await?;
+
+        let status = StpmStatus {
+            dsp: [sr1, sr2],
+            us: (us_reg3 >> 16) as u16,
+        };
+
+        // write 1 to clear
+        if sr1 != 0 {
+            self.write_register_32(Reg::DSP_SR1, sr1).await?;
+        }
+        if sr2 != 0 {
+            self.write_register_32(Reg::DSP_SR2, sr2).await?;
+        }
+        if status.us != 0 {
+            self.write_register_16_msw(Reg::US_REG3, status.us).await?;
+        }
+
+        Ok(status)
+    }
+
+    /// changes the current gain of a single channel at run time, together with
+    /// the current swell threshold that depends on it
+    pub async fn set_current_gain(&mut self, channel: usize, config: &StpmChannelConfiguration) -> Result<(), D::Error> {
+        let (reg_dfe, reg_dsp) = match channel {
+            0 => (Reg::DFE_CR1, Reg::DSP_CR6),
+            _ => (Reg::DFE_CR2, Reg::DSP_CR8),
+        };
+        // the gain is in the MSW
+        self.write_register_16_msw(reg_dfe, (config.dfe_cr() >> 16) as u16).await?;
+        self.write_register_32(reg_dsp, config.dsp_cr_current()).await
+    }
+
     pub async fn configure(&mut self, config: &StpmConfiguration) -> Result<(), D::Error> {
-        self.write_register_32(Reg::DSP_CR1, 0x040000a0).await?;
-        self.write_register_32(Reg::DSP_CR2, 0x240000a0).await?;
+        self.write_register_32(Reg::DSP_CR1, config.channels[0].dsp_cr_control(0x040000a0)).await?;
+        self.write_register_32(Reg::DSP_CR2, config.channels[1].dsp_cr_control(0x240000a0)).await?;
 
         let mut dsp_cr3 = 0x000004e0 | ((config.line_frequency as u32) << 27);
         dsp_cr3 |= 1 << 16; // enable ZCR output
@@ -70,11 +122,7 @@ impl<'a, D: StpmDriver> Stpm<'a, D> {
             val_a |= (config.channels[idx].voltage_swell_threshold as u32 & 0x3FF) << 12;
             val_a |= (config.channels[idx].voltage_sag_threshold as u32 & 0x3FF) << 22;
             self.write_register_32(reg_a, val_a).await?;
-
-            let mut val_b = 0;
-            val_b |= config.channels[idx].current_calibration as u32 & 0xFFF;
-            val_b |= (config.channels[idx].current_swell_threshold as u32 & 0x3FF) << 12;
-            self.write_register_32(reg_b, val_b).await?;
+            self.write_register_32(reg_b, config.channels[idx].dsp_cr_current()).await?;
         }
 
         // Ah accumulation -> only relevant for tamper detection
@@ -84,21 +132,24 @@ impl<'a, D: StpmDriver> Stpm<'a, D> {
         self.write_register_32(Reg::DSP_CR12, 0x00000fff).await?;
 
         // enable current and voltage channels 1+2
-        let dfe_cr1 = 0x03270327 | (config.channels[0].current_gain as u32) << 26;
-        let dfe_cr2 = 0x03270327 | (config.channels[1].current_gain as u32) << 26;
-        self.write_register_32(Reg::DFE_CR1, dfe_cr1).await?;
-        self.write_register_32(Reg::DFE_CR2, dfe_cr2).await?;
+        self.write_register_32(Reg::DFE_CR1, config.channels[0].dfe_cr()).await?;
+        self.write_register_32(Reg::DFE_CR2, config.channels[1].dfe_cr()).await?;
 
-        // disable IRQs
-        self.write_register_32(Reg::DSP_IRQ1, 0x00000000).await?;
-        self.write_register_32(Reg::DSP_IRQ2, 0x00000000).await?;
+        // IRQ masks
+        let dsp_mask = config.interrupts.dsp_mask();
+        self.write_register_32(Reg::DSP_IRQ1, dsp_mask).await?;
+        self.write_register_32(Reg::DSP_IRQ2, dsp_mask).await?;
 
         // CRC: enabled, poly=0x07, MSB first
         self.write_register_32(Reg::US_REG1, 0x00004007).await?;
-        // UART baud rate + delay
-        self.write_register_32(Reg::US_REG2, 0x00000683).await?;
-        // disable all communication-related interrupts
-        self.write_register_16_lsw(Reg::US_REG3, 0x0000).await?;
+        // communication-related interrupts
+        self.write_register_16_lsw(Reg::US_REG3, config.interrupts.us_mask()).await?;
+        // UART baud rate + delay, the new baud rate is active right after the
+        // LSW is written -> write it last and let the driver follow
+        let baud_div = (16_000_000 + config.baud_rate / 2) / config.baud_rate;
+        self.write_register_16_msw(Reg::US_REG2, 0x0000).await?;
+        self.write_register_16_lsw(Reg::US_REG2, baud_div as u16).await?;
+        self.driver.set_baud_rate(config.baud_rate).await?;
 
         Ok(())
     }
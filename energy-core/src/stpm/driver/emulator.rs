@@ -0,0 +1,240 @@
+use crate::stpm::{
+    chip::{Reg, US_CRC_ERROR},
+    sample::RawSampleChip,
+};
+
+use super::{StpmDriver, CRC_STPM};
+
+/// number of 32 bit registers in the STPM34 register file (up to TOT_REG4)
+const NUM_REGISTERS: usize = 70;
+
+#[derive(Debug)]
+pub enum StpmEmulatorError {
+    /// the emulated chip rejected a frame sent by the host
+    CrcErrorTx { expected: u8, received: u8 },
+    /// the host rejected a frame sent by the emulated chip
+    CrcErrorRx { expected: u8, received: u8 },
+}
+
+/// what the emulated chip measures at a SYN pulse
+#[derive(Copy, Clone, Debug, Default)]
+pub struct EmulatedMeasurement {
+    pub samples: [RawSampleChip; 2],
+    /// instantaneous voltage and current of each channel (DSP_REG2 to DSP_REG5)
+    pub instantaneous: [(i32, i32); 2],
+    /// bits to set in DSP_SR1 / DSP_SR2, they stay set until they are cleared
+    pub status: [u32; 2],
+}
+
+impl From<[RawSampleChip; 2]> for EmulatedMeasurement {
+    fn from(samples: [RawSampleChip; 2]) -> Self {
+        Self {
+            samples,
+            ..Default::default()
+        }
+    }
+}
+
+/// Register-level emulation of the STPM34, for running the measurement code
+/// without the chip.
+///
+/// Frames are encoded and checked exactly like on the SPI bus. A read request
+/// is answered in the following frame, like `chip::Reader` expects. Measurement
+/// registers only change on a SYN pulse, when `script` is called with the
+/// number of pulses seen so far and returns the values the chip would have
+/// measured at that point. Energy values are taken as absolute register
+/// values, so the script is responsible for integrating and wrapping them.
+pub struct StpmEmulator<S: FnMut(u32) -> EmulatedMeasurement> {
+    pub script: S,
+    /// number of SYN pulses since the last reset
+    pub syn_pulses: u32,
+    /// corrupt the CRC of this many responses, to test error handling
+    pub inject_crc_errors: u32,
+    /// number of frames the host sent since the last reset
+    pub transactions: u32,
+    registers: [u32; NUM_REGISTERS],
+    /// address requested in the previous frame
+    read_addr: u8,
+}
+
+impl<S: FnMut(u32) -> EmulatedMeasurement> StpmEmulator<S> {
+    pub fn new(script: S) -> Self {
+        let mut emulator = Self {
+            script,
+            syn_pulses: 0,
+            inject_crc_errors: 0,
+            transactions: 0,
+            registers: [0; NUM_REGISTERS],
+            read_addr: 0,
+        };
+        emulator.reset();
+        emulator
+    }
+
+    /// current content of a register, e.g. to check what the configuration wrote
+    pub fn register(&self, reg: Reg) -> u32 {
+        self.registers[reg as usize]
+    }
+
+    fn reset(&mut self) {
+        self.registers = [0; NUM_REGISTERS];
+        // reset values that matter for the communication
+        self.registers[Reg::US_REG1 as usize] = 0x00004007;
+        self.registers[Reg::US_REG2 as usize] = 0x00000683;
+        self.syn_pulses = 0;
+        self.transactions = 0;
+        self.read_addr = 0;
+    }
+
+    fn latch(&mut self, measurement: &EmulatedMeasurement) {
+        let [ph1, ph2] = &measurement.samples;
+        let r = &mut self.registers;
+
+        let [(v1, c1), (v2, c2)] = measurement.instantaneous;
+        r[Reg::DSP_REG2 as usize] = v1 as u32;
+        r[Reg::DSP_REG3 as usize] = c1 as u32;
+        r[Reg::DSP_REG4 as usize] = v2 as u32;
+        r[Reg::DSP_REG5 as usize] = c2 as u32;
+
+        r[Reg::DSP_SR1 as usize] |= measurement.status[0];
+        r[Reg::DSP_SR2 as usize] |= measurement.status[1];
+
+        r[Reg::DSP_REG14 as usize] = (ph1.voltage_rms & 0x7fff) | (ph1.current_rms & 0x1ffff) << 15;
+        r[Reg::DSP_REG15 as usize] = (ph2.voltage_rms & 0x7fff) | (ph2.current_rms & 0x1ffff) << 15;
+
+        r[Reg::DSP_REG1 as usize] = (ph1.period & 0xfff) | (ph2.period & 0xfff) << 16;
+
+        r[Reg::DSP_EV1 as usize] = ph1.events;
+        r[Reg::DSP_EV2 as usize] = ph2.events;
+        r[Reg::DSP_REG16 as usize] = ph1.voltage_event_time;
+        r[Reg::DSP_REG18 as usize] = ph2.voltage_event_time;
+        r[Reg::DSP_REG17 as usize] = (ph1.current_event_time & !0xfff) | (ph1.phase_angle & 0xfff);
+        r[Reg::DSP_REG19 as usize] = (ph2.current_event_time & !0xfff) | (ph2.phase_angle & 0xfff);
+
+        r[Reg::PH1_REG1 as usize] = ph1.energy_active;
+        r[Reg::PH1_REG2 as usize] = ph1.energy_fundamental;
+        r[Reg::PH1_REG3 as usize] = ph1.energy_reactive;
+        r[Reg::PH1_REG4 as usize] = ph1.energy_apparent;
+        r[Reg::PH1_REG5 as usize] = ph1.power_active as u32;
+        r[Reg::PH1_REG6 as usize] = ph1.power_fundamental as u32;
+        r[Reg::PH1_REG7 as usize] = ph1.power_reactive as u32;
+        r[Reg::PH1_REG8 as usize] = ph1.power_apparent as u32;
+        r[Reg::PH1_REG10 as usize] = ph1.power_momentary as u32;
+
+        r[Reg::PH2_REG1 as usize] = ph2.energy_active;
+        r[Reg::PH2_REG2 as usize] = ph2.energy_fundamental;
+        r[Reg::PH2_REG3 as usize] = ph2.energy_reactive;
+        r[Reg::PH2_REG4 as usize] = ph2.energy_apparent;
+        r[Reg::PH2_REG5 as usize] = ph2.power_active as u32;
+        r[Reg::PH2_REG6 as usize] = ph2.power_fundamental as u32;
+        r[Reg::PH2_REG7 as usize] = ph2.power_reactive as u32;
+        r[Reg::PH2_REG8 as usize] = ph2.power_apparent as u32;
+        r[Reg::PH2_REG10 as usize] = ph2.power_momentary as u32;
+    }
+
+    /// handles a frame on the chip side, returns the response frame
+    fn process(&mut self, buf_rx: &[u8; 5]) -> Result<[u8; 5], StpmEmulatorError> {
+        let crc_calc = CRC_STPM.checksum(&buf_rx[..4]);
+        if crc_calc != buf_rx[4] {
+            // the chip drops the frame and flags it in the MSW of US_REG3
+            self.registers[Reg::US_REG3 as usize] |= (US_CRC_ERROR as u32) << 16;
+            return Err(StpmEmulatorError::CrcErrorTx {
+                expected: crc_calc,
+                received: buf_rx[4],
+            });
+        }
+
+        // respond with the register requested in the previous frame
+        let value = self
+            .registers
+            .get(self.read_addr as usize / 2)
+            .copied()
+            .unwrap_or(0);
+        let mut buf_tx = [0u8; 5];
+        buf_tx[..4].copy_from_slice(&value.to_le_bytes());
+        buf_tx[4] = CRC_STPM.checksum(&buf_tx[..4]);
+
+        // write half of a register, even address = LSW, odd address = MSW
+        let write_addr = buf_rx[1];
+        if write_addr != 0xff {
+            let data = u16::from_le_bytes([buf_rx[2], buf_rx[3]]) as u32;
+            let index = write_addr as usize / 2;
+            let write_1_to_clear = index == Reg::DSP_SR1 as usize
+                || index == Reg::DSP_SR2 as usize
+                || (index == Reg::US_REG3 as usize && write_addr & 1 == 1);
+            if let Some(reg) = self.registers.get_mut(index) {
+                if write_1_to_clear {
+                    // status bits are cleared by writing a 1
+                    let shift = if write_addr & 1 == 0 { 0 } else { 16 };
+                    *reg &= !(data << shift);
+                } else if write_addr & 1 == 0 {
+                    *reg = (*reg & 0xffff0000) | data;
+                } else {
+                    *reg = (*reg & 0x0000ffff) | data << 16;
+                }
+            }
+        }
+
+        // 0xff keeps the read pointer where it is
+        if buf_rx[0] != 0xff {
+            self.read_addr = buf_rx[0];
+        }
+
+        Ok(buf_tx)
+    }
+}
+
+impl<S: FnMut(u32) -> EmulatedMeasurement> StpmDriver for StpmEmulator<S> {
+    type Error = StpmEmulatorError;
+
+    async fn transaction(
+        &mut self,
+        next_read_addr: Option<u8>,
+        write: Option<(u8, u16)>,
+    ) -> Result<u32, StpmEmulatorError> {
+        let mut buf_tx = [0u8; 5];
+        buf_tx[0] = next_read_addr.unwrap_or(0xff);
+        if let Some((addr, val)) = write {
+            buf_tx[1] = addr;
+            let val_buf = val.to_le_bytes();
+            buf_tx[2] = val_buf[0];
+            buf_tx[3] = val_buf[1];
+        } else {
+            buf_tx[1] = 0xff;
+        }
+        buf_tx[4] = CRC_STPM.checksum(&buf_tx[..4]);
+
+        self.transactions += 1;
+        let mut buf_rx = self.process(&buf_tx)?;
+
+        if self.inject_crc_errors > 0 {
+            self.inject_crc_errors -= 1;
+            buf_rx[4] ^= 0xff;
+        }
+
+        let crc_calc_rx = CRC_STPM.checksum(&buf_rx[..4]);
+
+        if crc_calc_rx != buf_rx[4] {
+            Err(StpmEmulatorError::CrcErrorRx {
+                expected: crc_calc_rx,
+                received: buf_rx[4],
+            })
+        } else {
+            let mut buf = [0u8; 4];
+            buf.copy_from_slice(&buf_rx[..4]);
+            Ok(u32::from_le_bytes(buf))
+        }
+    }
+
+    async fn hardware_reset(&mut self) -> Result<(), Self::Error> {
+        self.reset();
+        Ok(())
+    }
+
+    async fn syn_pulse(&mut self) -> Result<(), Self::Error> {
+        self.syn_pulses += 1;
+        let measurement = (self.script)(self.syn_pulses);
+        self.latch(&measurement);
+        Ok(())
+    }
+}
@@ -1,8 +1,12 @@
 pub mod spi;
+#[cfg(any(test, feature = "emulator"))]
+pub mod emulator;
+pub mod uart;
 
 use crc::{Crc, CRC_8_SMBUS};
 const CRC_STPM: Crc<u8> = Crc::<u8>::new(&CRC_8_SMBUS);
 
+#[allow(async_fn_in_trait)]
 pub trait StpmDriver {
     type Error;
 
@@ -19,4 +23,10 @@ pub trait StpmDriver {
     async fn hardware_reset(&mut self) -> Result<(), Self::Error>;
 
     async fn syn_pulse(&mut self) -> Result<(), Self::Error>;
+
+    // Called after a new baud rate has been written to US_REG2, so drivers using
+    // the UART interface can follow. SPI does not care about the baud rate.
+    async fn set_baud_rate(&mut self, _baud_rate: u32) -> Result<(), Self::Error> {
+        Ok(())
+    }
 }
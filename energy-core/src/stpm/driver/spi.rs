@@ -1,7 +1,6 @@
 use embassy_time::Timer;
 use embedded_hal::digital::OutputPin;
 use embedded_hal_async::spi::SpiBus;
-use esp_hal::gpio::{AnyPin, Output, PushPull};
 
 use super::{StpmDriver, CRC_STPM};
 
@@ -11,14 +10,14 @@ pub enum StpmSpiError {
     CrcErrorRx { expected: u8, received: u8 },
 }
 
-pub struct StpmSpiDriver<SPI: SpiBus> {
+pub struct StpmSpiDriver<SPI: SpiBus, PIN: OutputPin> {
     pub spi_device: SPI,
-    pub pin_scs: AnyPin<Output<PushPull>>,
-    pub pin_en: AnyPin<Output<PushPull>>,
-    pub pin_syn: AnyPin<Output<PushPull>>,
+    pub pin_scs: PIN,
+    pub pin_en: PIN,
+    pub pin_syn: PIN,
 }
 
-impl<SPI: SpiBus> StpmDriver for StpmSpiDriver<SPI> {
+impl<SPI: SpiBus, PIN: OutputPin> StpmDriver for StpmSpiDriver<SPI, PIN> {
     type Error = StpmSpiError;
 
     async fn transaction(
@@ -41,7 +40,7 @@ impl<SPI: SpiBus> StpmDriver for StpmSpiDriver<SPI> {
         let _ = self.pin_scs.set_low();
         Timer::after_micros(1).await;
         let mut buf_rx = [0u8; 5];
-        if let Err(_) = self.spi_device.transfer(&mut buf_rx, &buf_tx).await {
+        if self.spi_device.transfer(&mut buf_rx, &buf_tx).await.is_err() {
             return Err(StpmSpiError::Spi);
         }
         let _ = self.pin_scs.set_high();
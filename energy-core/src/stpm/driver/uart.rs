@@ -0,0 +1,371 @@
+use embassy_time::{with_timeout, Duration, Timer};
+use embedded_hal::digital::OutputPin;
+use embedded_io_async::{Read, ReadReady, Write};
+
+use super::{StpmDriver, CRC_STPM};
+
+/// the STPM UART always starts with 9600 baud after a reset
+pub const STPM_UART_DEFAULT_BAUD: u32 = 9600;
+/// baud rate set during the configuration. a sample read takes about 30
+/// frames, at 9600 baud that is ~160 ms and can't keep up with the sample loop
+pub const STPM_UART_BAUD: u32 = 115_200;
+/// baud rates the STPM UART supports
+pub const STPM_UART_BAUD_RANGE: core::ops::RangeInclusive<u32> = 2400..=921_600;
+
+#[derive(Debug)]
+pub enum StpmUartError {
+    Uart,
+    Timeout,
+    CrcErrorRx { expected: u8, received: u8 },
+}
+
+/// serial ports that can change their baud rate at run time
+pub trait BaudRate {
+    fn set_baud_rate(&mut self, baud_rate: u32);
+}
+
+pub struct StpmUartDriver<UART: Read + ReadReady + Write + BaudRate, PIN: OutputPin> {
+    pub uart: UART,
+    pub pin_scs: PIN,
+    pub pin_en: PIN,
+    pub pin_syn: PIN,
+    /// baud rate the host UART is currently running at
+    pub baud_rate: u32,
+}
+
+/// UART transmits LSB first, so the STPM calculates the CRC over bit-reversed
+/// bytes and sends the CRC bit-reversed as well
+fn checksum_uart(data: &[u8]) -> u8 {
+    let mut digest = CRC_STPM.digest();
+    for b in data {
+        digest.update(&[b.reverse_bits()]);
+    }
+    digest.finalize().reverse_bits()
+}
+
+impl<UART: Read + ReadReady + Write + BaudRate, PIN: OutputPin> StpmUartDriver<UART, PIN> {
+    /// time to wait for a response frame, two frame lengths plus some margin
+    fn timeout(&self) -> Duration {
+        // 5 bytes with 10 bits each (start + 8 data + stop)
+        let frame_us = 50 * 1_000_000 / self.baud_rate as u64;
+        Duration::from_micros(2 * frame_us + 5_000)
+    }
+
+    /// drops whatever is waiting in the rx buffer, e.g. the rest of a frame
+    /// that arrived after its timeout. it would shift every following frame.
+    async fn drain_rx(&mut self) -> Result<(), StpmUartError> {
+        let mut buf = [0u8; 16];
+        while self.uart.read_ready().map_err(|_| StpmUartError::Uart)? {
+            if self.uart.read(&mut buf).await.map_err(|_| StpmUartError::Uart)? == 0 {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<UART: Read + ReadReady + Write + BaudRate, PIN: OutputPin> StpmDriver for StpmUartDriver<UART, PIN> {
+    type Error = StpmUartError;
+
+    async fn transaction(
+        &mut self,
+        next_read_addr: Option<u8>,
+        write: Option<(u8, u16)>,
+    ) -> Result<u32, StpmUartError> {
+        let mut buf_tx = [0u8; 5];
+        buf_tx[0] = next_read_addr.unwrap_or(0xff);
+        if let Some((addr, val)) = write {
+            buf_tx[1] = addr;
+            let val_buf = val.to_le_bytes();
+            buf_tx[2] = val_buf[0];
+            buf_tx[3] = val_buf[1];
+        } else {
+            buf_tx[1] = 0xff;
+        }
+        buf_tx[4] = checksum_uart(&buf_tx[..4]);
+
+        self.drain_rx().await?;
+        if self.uart.write_all(&buf_tx).await.is_err() {
+            return Err(StpmUartError::Uart);
+        }
+
+        // the response is sent while the request is received, it is most
+        // likely waiting in the rx buffer already
+        let mut buf_rx = [0u8; 5];
+        match with_timeout(self.timeout(), self.uart.read_exact(&mut buf_rx)).await {
+            Ok(Ok(())) => (),
+            Ok(Err(_)) => return Err(StpmUartError::Uart),
+            Err(_) => return Err(StpmUartError::Timeout),
+        }
+
+        let crc_calc_rx = checksum_uart(&buf_rx[..4]);
+
+        if crc_calc_rx != buf_rx[4] {
+            Err(StpmUartError::CrcErrorRx {
+                expected: crc_calc_rx,
+                received: buf_rx[4],
+            })
+        } else {
+            let mut buf = [0u8; 4];
+            buf.copy_from_slice(&buf_rx[..4]);
+            Ok(u32::from_le_bytes(buf))
+        }
+    }
+
+    async fn hardware_reset(&mut self) -> Result<(), Self::Error> {
+        let _ = self.pin_syn.set_high();
+        // reset sequence for UART: SCS has to be high while EN rises
+        let _ = self.pin_en.set_low();
+        let _ = self.pin_scs.set_high();
+        Timer::after_millis(5).await;
+        let _ = self.pin_en.set_high();
+        Timer::after_millis(5).await;
+        // perform global reset
+        Timer::after_millis(35).await;
+        for _ in 0..3 {
+            // syn_pulse but with more delay
+            let _ = self.pin_syn.set_low();
+            Timer::after_millis(5).await;
+            let _ = self.pin_syn.set_high();
+            Timer::after_millis(5).await;
+        }
+        let _ = self.pin_scs.set_low();
+        Timer::after_millis(5).await;
+        let _ = self.pin_scs.set_high();
+
+        // chip is back at its default baud rate, anything received before
+        // is garbage now
+        self.uart.set_baud_rate(STPM_UART_DEFAULT_BAUD);
+        self.baud_rate = STPM_UART_DEFAULT_BAUD;
+        self.drain_rx().await
+    }
+
+    async fn syn_pulse(&mut self) -> Result<(), Self::Error> {
+        // SYN timings from table 4 (page 10)
+        // minimum pulse width: t_lpw = 4 us
+        // minimum pulse spacing: t_w = 4 us
+        let _ = self.pin_syn.set_low();
+        Timer::after_micros(10).await;
+        let _ = self.pin_syn.set_high();
+        Timer::after_micros(10).await;
+
+        Ok(())
+    }
+
+    async fn set_baud_rate(&mut self, baud_rate: u32) -> Result<(), Self::Error> {
+        // the response to the US_REG2 write has been received completely at
+        // this point, so it is safe to switch right away
+        self.uart.set_baud_rate(baud_rate);
+        self.baud_rate = baud_rate;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::VecDeque, convert::Infallible, rc::Rc};
+
+    use embassy_futures::block_on;
+
+    use super::*;
+
+    /// connects TX to RX, optionally corrupting or dropping bytes on the way
+    #[derive(Default)]
+    struct Loopback {
+        buffer: VecDeque<u8>,
+        /// xor the byte at this position of the next frame with 0xff
+        corrupt: Option<usize>,
+        /// lose the whole next frame
+        drop: bool,
+        /// only the first bytes of the next frame arrive in time, the rest
+        /// after its timeout
+        split: Option<usize>,
+        /// bytes that arrive after the timeout of their frame
+        late: Vec<u8>,
+        baud_rate: u32,
+    }
+
+    impl Loopback {
+        fn arrive_late(&mut self) {
+            self.buffer.extend(self.late.drain(..));
+        }
+    }
+
+    impl embedded_io_async::ErrorType for Loopback {
+        type Error = Infallible;
+    }
+
+    impl Write for Loopback {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+            self.arrive_late();
+            let mut frame = buf.to_vec();
+            if let Some(i) = self.corrupt.take() {
+                frame[i] ^= 0xff;
+            }
+            if let Some(i) = self.split.take() {
+                self.late.extend(frame.drain(i..));
+            }
+            if !core::mem::take(&mut self.drop) {
+                self.buffer.extend(frame);
+            }
+            Ok(buf.len())
+        }
+    }
+
+    impl Read for Loopback {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+            if self.buffer.is_empty() {
+                // nothing was sent, wait for the timeout
+                core::future::pending::<()>().await;
+            }
+            let n = buf.len().min(self.buffer.len());
+            for b in &mut buf[..n] {
+                *b = self.buffer.pop_front().unwrap();
+            }
+            Ok(n)
+        }
+    }
+
+    impl ReadReady for Loopback {
+        fn read_ready(&mut self) -> Result<bool, Infallible> {
+            self.arrive_late();
+            Ok(!self.buffer.is_empty())
+        }
+    }
+
+    impl BaudRate for Loopback {
+        fn set_baud_rate(&mut self, baud_rate: u32) {
+            self.baud_rate = baud_rate;
+        }
+    }
+
+    /// level changes of all pins in order
+    type PinLog = Rc<RefCell<Vec<(&'static str, bool)>>>;
+
+    #[derive(Clone)]
+    struct Pin {
+        name: &'static str,
+        log: PinLog,
+    }
+
+    impl embedded_hal::digital::ErrorType for Pin {
+        type Error = Infallible;
+    }
+
+    impl OutputPin for Pin {
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            self.log.borrow_mut().push((self.name, false));
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            self.log.borrow_mut().push((self.name, true));
+            Ok(())
+        }
+    }
+
+    fn driver() -> (StpmUartDriver<Loopback, Pin>, PinLog) {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let pin = |name| Pin { name, log: log.clone() };
+        let driver = StpmUartDriver {
+            uart: Loopback::default(),
+            pin_scs: pin("scs"),
+            pin_en: pin("en"),
+            pin_syn: pin("syn"),
+            baud_rate: STPM_UART_DEFAULT_BAUD,
+        };
+        (driver, log)
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let (mut driver, _) = driver();
+        // the looped back request is a valid response with the request bytes as data
+        let value = block_on(driver.transaction(Some(0x2e), Some((0x10, 0xbeef)))).unwrap();
+        assert_eq!(value, u32::from_le_bytes([0x2e, 0x10, 0xef, 0xbe]));
+
+        // no read address and no write
+        let value = block_on(driver.transaction(None, None)).unwrap();
+        assert_eq!(value, 0x0000_ffff);
+    }
+
+    #[test]
+    fn checksum_is_bit_reversed() {
+        // the CRC of the bit reversed bytes, sent bit reversed
+        let data: [u8; 4] = [0x04, 0xff, 0xff, 0xff];
+        let reversed: Vec<u8> = data.iter().map(|b| b.reverse_bits()).collect();
+        assert_eq!(checksum_uart(&data), CRC_STPM.checksum(&reversed).reverse_bits());
+        assert_ne!(checksum_uart(&data), CRC_STPM.checksum(&data));
+    }
+
+    #[test]
+    fn corrupted_frame() {
+        let (mut driver, _) = driver();
+        for i in 0..5 {
+            driver.uart.corrupt = Some(i);
+            let result = block_on(driver.transaction(Some(0x48), None));
+            assert!(matches!(result, Err(StpmUartError::CrcErrorRx { .. })), "byte {i}");
+        }
+        // the next frame is fine again
+        assert!(block_on(driver.transaction(Some(0x48), None)).is_ok());
+    }
+
+    #[test]
+    fn lost_frame() {
+        let (mut driver, _) = driver();
+        driver.uart.drop = true;
+        let result = block_on(driver.transaction(Some(0x48), None));
+        assert!(matches!(result, Err(StpmUartError::Timeout)));
+        assert!(block_on(driver.transaction(Some(0x48), None)).is_ok());
+    }
+
+    #[test]
+    fn partial_frame_is_drained() {
+        let expected = u32::from_le_bytes([0x48, 0xff, 0, 0]);
+        for i in 1..5 {
+            let (mut driver, _) = driver();
+            driver.uart.split = Some(i);
+            let result = block_on(driver.transaction(Some(0x48), None));
+            assert!(matches!(result, Err(StpmUartError::Timeout)), "split at {i}");
+            // the rest of the frame arrives late, the following frames must
+            // not be shifted by it
+            for _ in 0..3 {
+                assert_eq!(block_on(driver.transaction(Some(0x48), None)).unwrap(), expected, "split at {i}");
+            }
+        }
+    }
+
+    #[test]
+    fn timeout_follows_baud_rate() {
+        let (mut driver, _) = driver();
+        assert_eq!(driver.timeout(), Duration::from_micros(2 * 5208 + 5_000));
+
+        block_on(driver.set_baud_rate(STPM_UART_BAUD)).unwrap();
+        assert_eq!(driver.uart.baud_rate, STPM_UART_BAUD);
+        assert_eq!(driver.timeout(), Duration::from_micros(2 * 434 + 5_000));
+    }
+
+    #[test]
+    fn reset_sequence() {
+        let (mut driver, log) = driver();
+        block_on(driver.set_baud_rate(STPM_UART_BAUD)).unwrap();
+        // garbage received while the chip resets
+        driver.uart.buffer.extend([0x00, 0xff, 0x55]);
+        block_on(driver.hardware_reset()).unwrap();
+
+        // the chip starts at its default baud rate again
+        assert_eq!(driver.baud_rate, STPM_UART_DEFAULT_BAUD);
+        assert!(driver.uart.buffer.is_empty());
+        assert_eq!(driver.uart.baud_rate, STPM_UART_DEFAULT_BAUD);
+
+        // SCS is high when EN rises, that selects the UART interface
+        let log = log.borrow();
+        let en_high = log.iter().position(|&e| e == ("en", true)).unwrap();
+        let scs = log[..en_high].iter().rev().find(|(name, _)| *name == "scs");
+        assert_eq!(scs, Some(&("scs", true)));
+
+        // three SYN pulses for the global reset
+        let syn_pulses = log.iter().filter(|&&e| e == ("syn", false)).count();
+        assert_eq!(syn_pulses, 3);
+    }
+}
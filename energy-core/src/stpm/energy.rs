@@ -0,0 +1,356 @@
+use embassy_time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+
+use super::{mapping::PowerScale, noload::NoLoad, sample::RawSampleChip};
+
+/// fractional bits of the energy LSB and of the remainder of each total
+pub const ENERGY_FRACTION_BITS: u32 = 40;
+
+/// at full scale power the energy registers advance by about 2^30 per second.
+/// the wrapping difference of two reads is only unambiguous below 2^31, so
+/// after a longer pause the difference can not be trusted.
+const MAX_READ_GAP: Duration = Duration::from_millis(1500);
+
+/// calibrated energy total in mWh, plus the part below 1 mWh that has not
+/// been added yet
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct EnergyTotal {
+    pub value: i64,
+    /// always positive, with ENERGY_FRACTION_BITS fractional bits
+    remainder: i64,
+}
+
+impl EnergyTotal {
+    /// adds `raw` units of the chip register, `lsb` is the value of one unit
+    /// in mWh with ENERGY_FRACTION_BITS fractional bits
+    pub fn add(&mut self, raw: i64, lsb: i64) {
+        self.add_exact(raw as i128 * lsb as i128);
+    }
+
+    /// adds `energy` in mWh with ENERGY_FRACTION_BITS fractional bits
+    pub fn add_exact(&mut self, energy: i128) {
+        let sum = energy + self.remainder as i128;
+        // the shift rounds towards negative infinity, the remainder stays positive
+        self.value += (sum >> ENERGY_FRACTION_BITS) as i64;
+        self.remainder = (sum & ((1 << ENERGY_FRACTION_BITS) - 1)) as i64;
+    }
+}
+
+/// energy totals kept across reboots
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct EnergyAccumulator {
+    pub active: EnergyTotal,
+    pub fundamental: EnergyTotal,
+    pub reactive: EnergyTotal,
+    pub apparent: EnergyTotal,
+    /// active energy split up by direction, both only ever increase
+    pub active_import: EnergyTotal,
+    pub active_export: EnergyTotal,
+}
+
+/// energy totals of both channels and of their sum, kept across reboots
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct EnergyTotals {
+    pub channels: [EnergyAccumulator; 2],
+    /// active energy of the sum of both channels split up by the direction of
+    /// the summed power. adding up the import / export of the channels would
+    /// count what one channel exports into the other as both.
+    pub combined_import: EnergyTotal,
+    pub combined_export: EnergyTotal,
+}
+
+impl EnergyTotals {
+    /// converts the accumulator of the old firmware, the raw active energy
+    /// of both channels at current gain 16. `lsb` is the value of one unit
+    /// per channel, see `EnergyTotal::add`. it did not split up the
+    /// directions, so import and export start at zero.
+    pub fn from_legacy(raw: [i64; 2], lsb: [i64; 2]) -> Self {
+        let mut totals = Self::default();
+        for i in 0..2 {
+            totals.channels[i].active.add(raw[i], lsb[i]);
+        }
+        totals
+    }
+
+    /// adds the active energy of both channels of one tick, see
+    /// `EnergyIntegrator::update`
+    pub fn add_combined(&mut self, active: [Option<i128>; 2]) {
+        let sum: i128 = active.iter().flatten().sum();
+        self.combined_import.add_exact(sum.max(0));
+        self.combined_export.add_exact((-sum).max(0));
+    }
+}
+
+/// turns the wrapping 32 bit energy registers of one channel into increments
+/// of an EnergyAccumulator
+pub struct EnergyIntegrator {
+    /// active, fundamental, reactive, apparent
+    last: [u32; 4],
+    last_read: Instant,
+    /// value of one unit at current gain 16, see `EnergyTotal::add`
+    lsb: i64,
+}
+
+impl EnergyIntegrator {
+    /// the chip clears the energy registers on reset, create this right
+    /// after configuring it
+    pub fn new(now: Instant, lsb: i64) -> Self {
+        Self {
+            last: [0; 4],
+            last_read: now,
+            lsb,
+        }
+    }
+
+    /// adds the energy since the last read at `now` to `acc`.
+    /// `anti_current_gain` scales to current gain 16, `scale` is the voltage
+    /// mapping correction, see `mapping::VoltageMapper`.
+    /// totals of quantities in `no_load` stay unchanged.
+    /// returns the active energy added to `acc` (see `EnergyTotal::add_exact`),
+    /// None if the last read was too long ago, the difference is dropped then.
+    pub fn update(&mut self, now: Instant, raw: &RawSampleChip, anti_current_gain: i64, scale: PowerScale, no_load: NoLoad, acc: &mut EnergyAccumulator) -> Option<i128> {
+        let gap = now.duration_since(self.last_read) > MAX_READ_GAP;
+        self.last_read = now;
+
+        let regs = [raw.energy_active, raw.energy_fundamental, raw.energy_reactive, raw.energy_apparent];
+        let diff: [i64; 4] = core::array::from_fn(|i| regs[i].wrapping_sub(self.last[i]) as i32 as i64 * anti_current_gain);
+        self.last = regs;
+
+        if gap {
+            return None;
+        }
+
+        let [active, fundamental, reactive, apparent] = diff;
+        let (active, fundamental, reactive) = (scale.signed(active), scale.signed(fundamental), scale.signed(reactive));
+        let apparent = scale.unsigned(apparent);
+        let active = if no_load.active { 0 } else { active };
+        acc.active.add(active, self.lsb);
+        acc.active_import.add(active.max(0), self.lsb);
+        acc.active_export.add((-active).max(0), self.lsb);
+        if !no_load.reactive {
+            acc.reactive.add(reactive, self.lsb);
+        }
+        if !no_load.current {
+            acc.fundamental.add(fundamental, self.lsb);
+            acc.apparent.add(apparent, self.lsb);
+        }
+        Some(active as i128 * self.lsb as i128)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ONE: i64 = 1 << ENERGY_FRACTION_BITS;
+
+    /// value and remainder of a total as one fixed point number
+    fn exact(total: &EnergyTotal) -> i128 {
+        ((total.value as i128) << ENERGY_FRACTION_BITS) + total.remainder as i128
+    }
+
+    fn registers(active: u32, fundamental: u32, reactive: u32, apparent: u32) -> RawSampleChip {
+        RawSampleChip {
+            energy_active: active,
+            energy_fundamental: fundamental,
+            energy_reactive: reactive,
+            energy_apparent: apparent,
+            ..Default::default()
+        }
+    }
+
+    /// xorshift, reproducible without extra dependencies
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+    }
+
+    #[test]
+    fn remainder_carries() {
+        let mut total = EnergyTotal::default();
+        // 2^40 is not divisible by 3, three thirds stay just below 1 mWh
+        for _ in 0..3 {
+            total.add(1, ONE / 3);
+        }
+        assert_eq!(total.value, 0);
+        assert_eq!(total.remainder, ONE - 1);
+        total.add(1, ONE / 3);
+        assert_eq!(total.value, 1);
+        assert_eq!(total.remainder, ONE / 3 - 1);
+    }
+
+    #[test]
+    fn negative_rounds_down() {
+        let mut total = EnergyTotal::default();
+        total.add(-1, ONE / 4);
+        assert_eq!(total.value, -1);
+        assert_eq!(total.remainder, ONE * 3 / 4);
+        total.add(-4, ONE / 4);
+        assert_eq!(total.value, -2);
+        assert_eq!(total.remainder, ONE * 3 / 4);
+        total.add(1, ONE / 4);
+        assert_eq!((total.value, total.remainder), (-1, 0));
+        total.add(-3, ONE);
+        assert_eq!((total.value, total.remainder), (-4, 0));
+    }
+
+    #[test]
+    fn add_is_exact() {
+        let mut random = Random(0x1234_5678_9abc_def0);
+        let mut total = EnergyTotal::default();
+        let mut reference = 0i128;
+        for _ in 0..1_000_000 {
+            let raw = (random.next() as i64) >> 32;
+            let lsb = (random.next() >> 24) as i64;
+            total.add(raw, lsb);
+            reference += raw as i128 * lsb as i128;
+            assert!((0..ONE).contains(&total.remainder));
+        }
+        assert_eq!(exact(&total), reference);
+    }
+
+    #[test]
+    fn integrates_wrapping_registers() {
+        let mut random = Random(0x0fed_cba9_8765_4321);
+        let mut now = Instant::from_millis(0);
+        let (anti_gain, lsb) = (8, 0x35_1234_5678);
+        let mut integrator = EnergyIntegrator::new(now, lsb);
+        let mut acc = EnergyAccumulator::default();
+        let mut regs = [0u32; 4];
+        // active, fundamental, reactive, apparent, import, export
+        let mut reference = [0i128; 6];
+
+        for _ in 0..2_000_000 {
+            // up to the full scale of about 2^30 per second in either direction
+            let diff: [i64; 4] = core::array::from_fn(|_| (random.next() as i64) >> 38);
+            for i in 0..4 {
+                regs[i] = regs[i].wrapping_add(diff[i] as u32);
+            }
+            now += Duration::from_millis(50);
+
+            let raw = registers(regs[0], regs[1], regs[2], regs[3]);
+            assert!(integrator.update(now, &raw, anti_gain, PowerScale::ONE, NoLoad::default(), &mut acc).is_some());
+
+            let energy = diff.map(|d| (d * anti_gain) as i128 * lsb as i128);
+            for i in 0..4 {
+                reference[i] += energy[i];
+            }
+            reference[4] += energy[0].max(0);
+            reference[5] += (-energy[0]).max(0);
+        }
+
+        let totals = [acc.active, acc.fundamental, acc.reactive, acc.apparent, acc.active_import, acc.active_export];
+        for (total, reference) in totals.iter().zip(reference) {
+            assert_eq!(exact(total), reference);
+        }
+        // the registers wrapped many times
+        assert!(reference[4] >> ENERGY_FRACTION_BITS > (u32::MAX as i128 * anti_gain as i128 * lsb as i128) >> ENERGY_FRACTION_BITS);
+    }
+
+    #[test]
+    fn register_wrap() {
+        let mut now = Instant::from_millis(0);
+        let mut integrator = EnergyIntegrator::new(now, ONE);
+        let mut acc = EnergyAccumulator::default();
+
+        for (reg, expected) in [(u32::MAX - 9, -10), (5, -10 + 15), (u32::MAX, -10 + 15 - 6)] {
+            now += Duration::from_millis(50);
+            let raw = registers(reg, 0, 0, 0);
+            assert!(integrator.update(now, &raw, 1, PowerScale::ONE, NoLoad::default(), &mut acc).is_some());
+            assert_eq!(acc.active.value, expected);
+        }
+        assert_eq!(acc.active_import.value, 15);
+        assert_eq!(acc.active_export.value, 16);
+    }
+
+    #[test]
+    fn gap_is_dropped() {
+        let mut now = Instant::from_millis(0);
+        let mut integrator = EnergyIntegrator::new(now, ONE);
+        let mut acc = EnergyAccumulator::default();
+
+        now += MAX_READ_GAP;
+        assert!(integrator.update(now, &registers(10, 10, 10, 10), 1, PowerScale::ONE, NoLoad::default(), &mut acc).is_some());
+        assert_eq!(acc.active.value, 10);
+
+        now += MAX_READ_GAP + Duration::from_millis(1);
+        assert!(integrator.update(now, &registers(1000, 1000, 1000, 1000), 1, PowerScale::ONE, NoLoad::default(), &mut acc).is_none());
+        assert_eq!(acc.active.value, 10);
+        assert_eq!(acc.apparent.value, 10);
+
+        // continues from the registers read after the gap
+        now += Duration::from_millis(50);
+        assert!(integrator.update(now, &registers(1001, 1001, 1001, 1001), 1, PowerScale::ONE, NoLoad::default(), &mut acc).is_some());
+        assert_eq!(acc.active.value, 11);
+        assert_eq!(acc.fundamental.value, 11);
+    }
+
+    #[test]
+    fn no_load_freezes_totals() {
+        let mut now = Instant::from_millis(0);
+        let mut integrator = EnergyIntegrator::new(now, ONE);
+        let mut acc = EnergyAccumulator::default();
+
+        let no_active = NoLoad {
+            active: true,
+            ..Default::default()
+        };
+        now += Duration::from_millis(50);
+        assert!(integrator.update(now, &registers(10, 20, 30, 40), 1, PowerScale::ONE, no_active, &mut acc).is_some());
+        assert_eq!([acc.active.value, acc.active_import.value, acc.active_export.value], [0; 3]);
+        assert_eq!([acc.fundamental.value, acc.reactive.value, acc.apparent.value], [20, 30, 40]);
+
+        let no_current = NoLoad {
+            current: true,
+            active: true,
+            reactive: true,
+        };
+        now += Duration::from_millis(50);
+        assert!(integrator.update(now, &registers(20, 40, 60, 80), 1, PowerScale::ONE, no_current, &mut acc).is_some());
+        assert_eq!([acc.active.value, acc.fundamental.value, acc.reactive.value, acc.apparent.value], [0, 20, 30, 40]);
+
+        // the frozen energy is not added later
+        now += Duration::from_millis(50);
+        assert!(integrator.update(now, &registers(21, 41, 61, 81), 1, PowerScale::ONE, NoLoad::default(), &mut acc).is_some());
+        assert_eq!([acc.active.value, acc.fundamental.value, acc.reactive.value, acc.apparent.value], [1, 21, 31, 41]);
+    }
+
+    #[test]
+    fn combined_follows_the_summed_power() {
+        let mut now = Instant::from_millis(0);
+        let mut integrators = [EnergyIntegrator::new(now, ONE), EnergyIntegrator::new(now, ONE / 2)];
+        let mut totals = EnergyTotals::default();
+        let mut regs = [0u32; 2];
+
+        // channel 1 imports 10, channel 2 exports 8 * 1/2 into it
+        for (step, (a, b)) in [(10i32, -8i32), (10, -8), (-2, -8)].into_iter().enumerate() {
+            now += Duration::from_millis(50);
+            regs[0] = regs[0].wrapping_add(a as u32);
+            regs[1] = regs[1].wrapping_add(b as u32);
+            let active: [_; 2] = core::array::from_fn(|i| {
+                integrators[i].update(now, &registers(regs[i], 0, 0, 0), 1, PowerScale::ONE, NoLoad::default(), &mut totals.channels[i])
+            });
+            totals.add_combined(active);
+            let expected = [(6, 0), (12, 0), (12, 6)][step];
+            assert_eq!((totals.combined_import.value, totals.combined_export.value), expected);
+        }
+        // the channels see 20 import and 14 export in total
+        assert_eq!(totals.channels[0].active_import.value + totals.channels[1].active_import.value, 20);
+        assert_eq!(totals.channels[0].active_export.value + totals.channels[1].active_export.value, 14);
+    }
+
+    #[test]
+    fn legacy_is_converted() {
+        let totals = EnergyTotals::from_legacy([12, -5], [ONE / 4, ONE * 2]);
+        assert_eq!(totals.channels[0].active.value, 3);
+        assert_eq!(totals.channels[1].active.value, -10);
+        assert_eq!(totals.channels[1].active_export.value, 0);
+        assert_eq!(totals.combined_import.value, 0);
+    }
+}
@@ -0,0 +1,151 @@
+use serde::{Deserialize, Serialize};
+
+use super::sample::RawSampleChip;
+
+/// fractional bits of `PowerScale`
+const SCALE_BITS: u32 = 16;
+
+/// the own voltage has to be at least this fraction (1 / n) of the mapped
+/// voltage, below that the ratio mostly amplifies noise
+const MIN_OWN_VOLTAGE_DIVISOR: u64 = 8;
+
+/// correction of the powers of a single reading, negative if the voltage is
+/// inverted
+#[derive(Copy, Clone, Debug)]
+pub struct PowerScale {
+    factor: i64,
+}
+
+impl PowerScale {
+    pub const ONE: Self = Self { factor: 1 << SCALE_BITS };
+    pub const ZERO: Self = Self { factor: 0 };
+
+    /// active, reactive and fundamental power follow the inversion
+    pub fn signed(&self, value: i64) -> i64 {
+        (value * self.factor) >> SCALE_BITS
+    }
+
+    /// the apparent power stays positive
+    pub fn unsigned(&self, value: i64) -> i64 {
+        (value * self.factor.abs()) >> SCALE_BITS
+    }
+}
+
+/// voltage that is paired with the current of a channel, the chip always
+/// multiplies with its own voltage input so the powers are corrected in
+/// software
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+pub struct VoltageMapping {
+    // channel (1 or 2) whose voltage backs the current, None = own voltage
+    pub voltage_channel: Option<usize>,
+    // the voltage is in antiphase, e.g. the other leg of a split-phase supply
+    pub invert: bool,
+}
+
+/// pairs the current of a channel with the voltage of another channel.
+/// the chip only multiplies with the channel's own voltage input, so the
+/// powers are scaled by the ratio of the two voltages. this is only right if
+/// both voltages have the same waveform and phase (e.g. the voltage inputs
+/// are wired in parallel), the phase angle and the period stay those of the
+/// own voltage. the own voltage input has to be connected, otherwise the
+/// chip measures no power that could be scaled.
+pub struct VoltageMapper {
+    /// index of the voltage channel
+    source: usize,
+    invert: bool,
+    /// value of one unit of the source voltage in units of the own voltage,
+    /// with SCALE_BITS fractional bits
+    lsb_ratio: u64,
+}
+
+impl VoltageMapper {
+    /// `voltage_lsb` is the calibrated value of one unit of the voltage
+    /// register of each channel
+    pub fn new(channel: usize, mapping: &VoltageMapping, voltage_lsb: [f32; 2]) -> Self {
+        let source = mapping.voltage_channel.map_or(channel, |ch| ch - 1);
+        let ratio = voltage_lsb[source] / voltage_lsb[channel];
+        Self {
+            source,
+            invert: mapping.invert,
+            lsb_ratio: (ratio * (1 << SCALE_BITS) as f32) as u64,
+        }
+    }
+
+    /// replaces the voltage of `raw` with the source voltage, `voltages` are
+    /// the raw voltages of both channels before any mapping.
+    /// the powers of `raw` have to be scaled with the result, None if the own
+    /// voltage is too low to derive the powers from it.
+    pub fn apply(&self, raw: &mut RawSampleChip, voltages: [u32; 2], channel: usize) -> Option<PowerScale> {
+        let sign = if self.invert { -1 } else { 1 };
+        if self.source == channel {
+            return Some(PowerScale { factor: sign * PowerScale::ONE.factor });
+        }
+
+        let own = voltages[channel] as u64;
+        let mapped = (voltages[self.source] as u64 * self.lsb_ratio) >> SCALE_BITS;
+        raw.voltage_rms = mapped.min(u32::MAX as u64) as u32;
+
+        // without any voltage there is no power either
+        if mapped == 0 {
+            return Some(PowerScale::ZERO);
+        }
+        if own * MIN_OWN_VOLTAGE_DIVISOR < mapped {
+            return None;
+        }
+        Some(PowerScale {
+            factor: sign * ((mapped << SCALE_BITS) / own) as i64,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapper(invert: bool, voltage_lsb: [f32; 2]) -> VoltageMapper {
+        VoltageMapper::new(1, &VoltageMapping { voltage_channel: Some(1), invert }, voltage_lsb)
+    }
+
+    fn raw(voltage_rms: u32) -> RawSampleChip {
+        RawSampleChip { voltage_rms, ..Default::default() }
+    }
+
+    #[test]
+    fn own_voltage_is_kept() {
+        let mapper = VoltageMapper::new(1, &VoltageMapping { voltage_channel: None, invert: true }, [1.0, 2.0]);
+        let mut sample = raw(1000);
+        let scale = mapper.apply(&mut sample, [2000, 1000], 1).unwrap();
+        assert_eq!(sample.voltage_rms, 1000);
+        assert_eq!(scale.signed(1000), -1000);
+        assert_eq!(scale.unsigned(1000), 1000);
+    }
+
+    #[test]
+    fn powers_follow_the_voltage_ratio() {
+        // channel 1 has twice the resolution, its 2000 units equal 1000 own units
+        let mapper = mapper(true, [0.5, 1.0]);
+        let mut sample = raw(500);
+        let scale = mapper.apply(&mut sample, [2000, 500], 1).unwrap();
+        assert_eq!(sample.voltage_rms, 1000);
+        assert_eq!(scale.signed(9000), -18000);
+        assert_eq!(scale.unsigned(9000), 18000);
+    }
+
+    #[test]
+    fn low_own_voltage_is_rejected() {
+        let mapper = mapper(false, [1.0, 1.0]);
+        let mut sample = raw(0);
+        assert!(mapper.apply(&mut sample, [1000, 0], 1).is_none());
+        // the voltage is still the mapped one
+        assert_eq!(sample.voltage_rms, 1000);
+        assert!(mapper.apply(&mut raw(124), [1000, 124], 1).is_none());
+        assert!(mapper.apply(&mut raw(125), [1000, 125], 1).is_some());
+    }
+
+    #[test]
+    fn no_voltage_is_no_power() {
+        let mapper = mapper(false, [1.0, 1.0]);
+        let scale = mapper.apply(&mut raw(0), [0, 0], 1).unwrap();
+        assert_eq!(scale.signed(1000), 0);
+    }
+}
@@ -0,0 +1,7 @@
+pub mod calibration;
+pub mod chip;
+pub mod driver;
+pub mod energy;
+pub mod mapping;
+pub mod noload;
+pub mod sample;
@@ -0,0 +1,8 @@
+/// quantities of a single reading that were below their no-load threshold
+#[derive(Copy, Clone, Debug, Default)]
+pub struct NoLoad {
+    /// no current, nothing is measured on this channel
+    pub current: bool,
+    pub active: bool,
+    pub reactive: bool,
+}
@@ -0,0 +1,382 @@
+use crate::stpm::chip::Reader;
+
+use super::{
+    calibration::RmsMode,
+    chip::{Stpm, StpmCurrentGain},
+    driver::StpmDriver,
+};
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct RawSampleChip {
+    pub voltage_rms: u32,
+    pub current_rms: u32,
+    pub power_active: i32,
+    pub power_reactive: i32,
+    pub power_apparent: i32,
+    pub power_fundamental: i32,
+    /// unfiltered active power from PH1_REG10 / PH2_REG10
+    pub power_momentary: i32,
+    pub energy_active: u32,
+    pub energy_fundamental: u32,
+    pub energy_reactive: u32,
+    pub energy_apparent: u32,
+    /// live event register DSP_EV1 / DSP_EV2
+    pub events: u32,
+    /// sag and swell time register DSP_REG16 / DSP_REG18
+    pub voltage_event_time: u32,
+    /// current swell time register DSP_REG17 / DSP_REG19
+    pub current_event_time: u32,
+    /// delay from the voltage to the current zero crossing, in periods of
+    /// PHASE_CLOCK_HZ, from the lower 12 bits of DSP_REG17 / DSP_REG19
+    pub phase_angle: u32,
+    /// line period in periods of PERIOD_CLOCK_HZ, from DSP_REG1
+    pub period: u32,
+}
+
+/// the period counters run at 125 kHz
+pub const PERIOD_CLOCK_HZ: u32 = 125_000;
+
+/// the phase angle counters run at 125 kHz
+pub const PHASE_CLOCK_HZ: u32 = 125_000;
+
+/// converts the zero crossing delay into a signed delay of at most half a
+/// period, positive if the current lags the voltage (inductive load)
+pub fn signed_phase(phase_angle: u32, line_frequency: u32) -> i32 {
+    let period = (PHASE_CLOCK_HZ / line_frequency) as i32;
+    let phase = phase_angle as i32 % period;
+    if phase > period / 2 {
+        phase - period
+    } else {
+        phase
+    }
+}
+
+/// consecutive read errors the sample loop tolerates before it resets the
+/// chip, every successful read takes one error back
+#[derive(Debug, Default)]
+pub struct ReadErrors {
+    count: u32,
+}
+
+impl ReadErrors {
+    pub const MAX: u32 = 3;
+
+    /// records a failed read, returns true if there were too many and the
+    /// chip should be reset
+    pub fn exhausted(&mut self) -> bool {
+        if self.count >= Self::MAX {
+            return true;
+        }
+        self.count += 1;
+        false
+    }
+
+    pub fn succeeded(&mut self) {
+        self.count = self.count.saturating_sub(1);
+    }
+}
+
+/// `poll_events`: read the live event and event time registers, not needed if
+/// all events are reported through the status registers
+pub async fn read_samples<'a, D: StpmDriver>(chip: &mut Stpm<'a, D>, sample: &mut [RawSampleChip; 2], poll_events: bool) -> Result<(), D::Error> {
+    chip.driver.syn_pulse().await?;
+    
+    
+    let (mut ph1_rms, mut ph2_rms, mut period) = (0, 0, 0);
+
+    let [ph1, ph2] = sample;
+
+    let mut reader = Reader::create(chip);
+    
+    use super::chip::Reg::*;
+    if poll_events {
+        reader = reader
+            .read_u32(DSP_EV1, &mut ph1.events).await?
+            .read_u32(DSP_EV2, &mut ph2.events).await?
+            .read_u32(DSP_REG16, &mut ph1.voltage_event_time).await?
+            .read_u32(DSP_REG18, &mut ph2.voltage_event_time).await?;
+    }
+    reader
+        .read_u32(DSP_REG1, &mut period).await?
+        .read_u32(DSP_REG17, &mut ph1.current_event_time).await?
+        .read_u32(DSP_REG19, &mut ph2.current_event_time).await?
+        .read_u32(DSP_REG14, &mut ph1_rms).await?
+        .read_u32(DSP_REG15, &mut ph2_rms).await?
+        .read_u32(PH1_REG1, &mut ph1.energy_active).await?
+        .read_u32(PH1_REG2, &mut ph1.energy_fundamental).await?
+        .read_u32(PH1_REG3, &mut ph1.energy_reactive).await?
+        .read_u32(PH1_REG4, &mut ph1.energy_apparent).await?
+        .read_i32(PH1_REG5, &mut ph1.power_active).await?
+        .read_i32(PH1_REG6, &mut ph1.power_fundamental).await?
+        .read_i32(PH1_REG7, &mut ph1.power_reactive).await?
+        .read_i32(PH1_REG8, &mut ph1.power_apparent).await?
+        .read_i32(PH1_REG10, &mut ph1.power_momentary).await?
+        .read_u32(PH2_REG1, &mut ph2.energy_active).await?
+        .read_u32(PH2_REG2, &mut ph2.energy_fundamental).await?
+        .read_u32(PH2_REG3, &mut ph2.energy_reactive).await?
+        .read_u32(PH2_REG4, &mut ph2.energy_apparent).await?
+        .read_i32(PH2_REG5, &mut ph2.power_active).await?
+        .read_i32(PH2_REG6, &mut ph2.power_fundamental).await?
+        .read_i32(PH2_REG7, &mut ph2.power_reactive).await?
+        .read_i32(PH2_REG8, &mut ph2.power_apparent).await?
+        .read_i32(PH2_REG10, &mut ph2.power_momentary).await?
+        .end().await?;
+
+    ph1.voltage_rms = ph1_rms & ((1 << 15) - 1);
+    ph2.voltage_rms = ph2_rms & ((1 << 15) - 1);
+
+    ph1.current_rms = (ph1_rms >> 15) & ((1 << 17) - 1);
+    ph2.current_rms = (ph2_rms >> 15) & ((1 << 17) - 1);
+
+    ph1.period = period & 0xfff;
+    ph2.period = (period >> 16) & 0xfff;
+
+    ph1.phase_angle = ph1.current_event_time & 0xfff;
+    ph2.phase_angle = ph2.current_event_time & 0xfff;
+
+    Ok(())
+}
+
+/// smallest and largest single sample within a window
+#[derive(Copy, Clone, Debug, Default)]
+pub struct MinMax<T> {
+    pub min: T,
+    pub max: T,
+}
+
+impl<T: Copy + Ord> MinMax<T> {
+    pub fn update(&mut self, value: T, first: bool) {
+        if first {
+            self.min = value;
+            self.max = value;
+        } else {
+            self.min = self.min.min(value);
+            self.max = self.max.max(value);
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct RawSampleApp {
+    pub voltage_rms: u64,
+    // all raw values are scaled to current gain of 16
+    pub current_rms: u64,
+    /// sums of the squared RMS readings
+    pub voltage_rms_sq: u128,
+    pub current_rms_sq: u128,
+    pub rms_mode: RmsMode,
+    pub power_active: i64,
+    pub power_reactive: i64,
+    pub power_apparent: i64,
+    pub power_fundamental: i64,
+    /// single samples, normalized to current gain 16 like the sums
+    pub voltage_rms_range: MinMax<u64>,
+    pub current_rms_range: MinMax<u64>,
+    pub power_active_range: MinMax<i64>,
+    /// sum of the signed phase angle counts, see `signed_phase`
+    pub phase_angle: i64,
+    /// sum of the valid line periods and their number
+    pub period: u64,
+    pub period_samples: usize,
+    /// readings whose powers were zeroed by the no-load detection: active
+    /// power, reactive power and, without current, everything else. they
+    /// don't carry the power offsets.
+    pub no_load_active: usize,
+    pub no_load_reactive: usize,
+    pub no_load_current: usize,
+    /// energy totals in mWh
+    pub energy_active: i64,
+    pub energy_fundamental: i64,
+    pub energy_reactive: i64,
+    pub energy_apparent: i64,
+    pub energy_import: i64,
+    pub energy_export: i64,
+    /// import / export of the sum of both channels, the same in both samples
+    pub energy_combined_import: i64,
+    pub energy_combined_export: i64,
+    /// energy was dropped during this window, see `energy::EnergyIntegrator`
+    pub energy_gap: bool,
+    /// a reading lacked the own voltage to derive the mapped powers from,
+    /// see `mapping::VoltageMapper`
+    pub mapping_failed: bool,
+    /// the chip detected a current swell during this window
+    pub current_swell: bool,
+    /// current gain at the end of this window
+    pub current_gain: StpmCurrentGain,
+    pub num_samples: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+
+    use super::*;
+    use crate::stpm::{
+        chip::{Reg, StpmConfiguration, SR_VOLTAGE_SAG_START, SR_VOLTAGE_SWELL_START},
+        driver::{
+            emulator::{EmulatedMeasurement, StpmEmulator},
+            uart::STPM_UART_BAUD,
+        },
+    };
+
+    /// distinct values in every field, changing with every SYN pulse
+    fn sample(channel: u32, pulse: u32) -> RawSampleChip {
+        let n = pulse * 16 + channel;
+        RawSampleChip {
+            voltage_rms: 0x4000 + n,
+            current_rms: 0x10000 + n,
+            power_active: -1000 - n as i32,
+            power_reactive: 2000 + n as i32,
+            power_apparent: 3000 + n as i32,
+            power_fundamental: -4000 - n as i32,
+            power_momentary: -5000 - n as i32,
+            energy_active: u32::MAX - n,
+            energy_fundamental: 6000 + n,
+            energy_reactive: 7000 + n,
+            energy_apparent: 8000 + n,
+            events: 1 << (n % 32),
+            voltage_event_time: 0x0001_0000 + n,
+            current_event_time: 0x0002_0000 | (0x100 + n),
+            phase_angle: 0x100 + n,
+            period: 0x9c4 + n,
+        }
+    }
+
+    fn script(pulse: u32) -> EmulatedMeasurement {
+        [sample(0, pulse), sample(1, pulse)].into()
+    }
+
+    /// reads samples like the sample loop, the reads for which `fail` returns
+    /// true get a corrupted response. returns the number of successful reads
+    /// or None if the chip would have been reset
+    fn sample_loop<S: FnMut(u32) -> EmulatedMeasurement>(
+        emulator: &mut StpmEmulator<S>,
+        mut fail: impl FnMut(u32) -> bool,
+        reads: u32,
+    ) -> Option<u32> {
+        let mut chip = Stpm::new(emulator);
+        let mut samples = Default::default();
+        let mut errors = ReadErrors::default();
+        let mut ok = 0;
+        for i in 0..reads {
+            chip.driver.inject_crc_errors = fail(i) as u32;
+            match block_on(read_samples(&mut chip, &mut samples, true)) {
+                Ok(()) => {
+                    errors.succeeded();
+                    ok += 1;
+                }
+                Err(_) => {
+                    if errors.exhausted() {
+                        return None;
+                    }
+                }
+            }
+        }
+        Some(ok)
+    }
+
+    #[test]
+    fn read_samples_decodes_registers() {
+        let mut emulator = StpmEmulator::new(script);
+        let mut chip = Stpm::new(&mut emulator);
+        let mut samples = Default::default();
+
+        for pulse in 1..=3 {
+            block_on(read_samples(&mut chip, &mut samples, true)).unwrap();
+            assert_eq!(chip.driver.syn_pulses, pulse);
+            assert_eq!(samples, [sample(0, pulse), sample(1, pulse)]);
+        }
+    }
+
+    #[test]
+    fn read_samples_without_events() {
+        let mut emulator = StpmEmulator::new(script);
+        let mut chip = Stpm::new(&mut emulator);
+        let mut samples: [RawSampleChip; 2] = Default::default();
+
+        block_on(read_samples(&mut chip, &mut samples, false)).unwrap();
+        for (i, raw) in samples.iter().enumerate() {
+            let expected = RawSampleChip {
+                events: 0,
+                voltage_event_time: 0,
+                ..sample(i as u32, 1)
+            };
+            assert_eq!(*raw, expected);
+        }
+    }
+
+    #[test]
+    fn phase_is_signed() {
+        // 125 kHz / 50 Hz = 2500 counts per period
+        assert_eq!(signed_phase(100, 50), 100);
+        assert_eq!(signed_phase(1250, 50), 1250);
+        assert_eq!(signed_phase(1251, 50), -1249);
+        assert_eq!(signed_phase(2600, 50), 100);
+    }
+
+    #[test]
+    fn read_errors_are_tolerated() {
+        let mut emulator = StpmEmulator::new(script);
+        // three reads in a row fail, the fourth works
+        assert_eq!(sample_loop(&mut emulator, |i| (5..8).contains(&i), 10), Some(7));
+        // every other read fails, the successful reads take the errors back
+        assert_eq!(sample_loop(&mut emulator, |i| i % 2 == 1, 100), Some(50));
+    }
+
+    #[test]
+    fn too_many_read_errors_reset() {
+        let mut emulator = StpmEmulator::new(script);
+        // four reads in a row fail
+        assert_eq!(sample_loop(&mut emulator, |i| (5..9).contains(&i), 10), None);
+        // two errors for every success add up
+        assert_eq!(sample_loop(&mut emulator, |i| i % 3 != 0, 100), None);
+    }
+
+    #[test]
+    fn status_is_latched_until_cleared() {
+        let mut emulator = StpmEmulator::new(|pulse| EmulatedMeasurement {
+            status: match pulse {
+                1 => [SR_VOLTAGE_SAG_START, 0],
+                2 => [0, SR_VOLTAGE_SWELL_START],
+                _ => [0; 2],
+            },
+            ..script(pulse)
+        });
+        let mut chip = Stpm::new(&mut emulator);
+        let mut samples = Default::default();
+
+        block_on(read_samples(&mut chip, &mut samples, true)).unwrap();
+        block_on(read_samples(&mut chip, &mut samples, true)).unwrap();
+        let status = block_on(chip.read_clear_status()).unwrap();
+        assert_eq!(status.dsp, [SR_VOLTAGE_SAG_START, SR_VOLTAGE_SWELL_START]);
+        assert_eq!(status.us, 0);
+
+        block_on(read_samples(&mut chip, &mut samples, true)).unwrap();
+        let status = block_on(chip.read_clear_status()).unwrap();
+        assert_eq!(status.dsp, [0; 2]);
+    }
+
+    #[test]
+    fn instantaneous_values() {
+        let mut emulator = StpmEmulator::new(|pulse| EmulatedMeasurement {
+            instantaneous: [(-100, 200), (300, -400 * pulse as i32)],
+            ..script(pulse)
+        });
+        let mut chip = Stpm::new(&mut emulator);
+
+        block_on(chip.driver.syn_pulse()).unwrap();
+        let values = [Reg::DSP_REG2, Reg::DSP_REG3, Reg::DSP_REG4, Reg::DSP_REG5]
+            .map(|reg| block_on(chip.read_register(reg)).unwrap() as i32);
+        assert_eq!(values, [-100, 200, 300, -400]);
+    }
+
+    #[test]
+    fn configure_sets_baud_rate() {
+        let mut emulator = StpmEmulator::new(script);
+        let mut chip = Stpm::new(&mut emulator);
+
+        block_on(chip.configure(&StpmConfiguration::default())).unwrap();
+        let divider = (16_000_000 + STPM_UART_BAUD / 2) / STPM_UART_BAUD;
+        assert_eq!(emulator.register(Reg::US_REG2) & 0xffff, divider);
+    }
+}
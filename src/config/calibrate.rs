@@ -0,0 +1,507 @@
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embassy_time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    stpm::{
+        calibration::{conversion_parameters, IntCalibratedSample},
+        line_frequency, RawSampleApp, StpmChannelConfiguration,
+    },
+    zcr,
+};
+
+use super::{CalibrationConfig, CurrentSensor, PhaseCompensation, Signal, StpmConfig};
+
+/// windows to average if the request does not say
+const DEFAULT_WINDOWS: usize = 10;
+const MAX_WINDOWS: usize = 60;
+/// a window takes about a second with the default settings
+const MAX_WINDOW_TIME: Duration = Duration::from_secs(10);
+/// the phase can only be calibrated if the reactive power is at least this
+/// fraction of the active power
+const MIN_REACTIVE_RATIO: f32 = 0.3;
+/// more current than this is a load, not an offset
+const MAX_OFFSET_CURRENT: f32 = 0.5;
+
+/// result of a calibration step, applied by `/calibrate_apply`. only the
+/// calibrated values are applied, other changes made in the meantime stay.
+pub enum PendingCalibration {
+    Reference {
+        channel: usize,
+        voltage_divider_factor: f32,
+        current_sensor: CurrentSensor,
+    },
+    Phase {
+        channel: usize,
+        compensation: PhaseCompensation,
+    },
+    Offset {
+        channel: usize,
+        current_offset: f32,
+        active_power_offset: f32,
+        reactive_power_offset: f32,
+    },
+}
+
+impl PendingCalibration {
+    pub fn apply(&self, calibration: &mut CalibrationConfig, stpm: &mut StpmConfig) {
+        match *self {
+            PendingCalibration::Reference {
+                channel,
+                voltage_divider_factor,
+                current_sensor,
+            } => {
+                calibration.channels[channel].voltage_divider_factor = voltage_divider_factor;
+                calibration.channels[channel].current_sensor = current_sensor;
+            }
+            PendingCalibration::Phase { channel, compensation } => {
+                stpm.phase_compensation[channel] = compensation;
+            }
+            PendingCalibration::Offset {
+                channel,
+                current_offset,
+                active_power_offset,
+                reactive_power_offset,
+            } => {
+                let channel = &mut calibration.channels[channel];
+                channel.current_offset = current_offset;
+                channel.active_power_offset = active_power_offset;
+                channel.reactive_power_offset = reactive_power_offset;
+            }
+        }
+    }
+}
+
+pub static PENDING: Mutex<CriticalSectionRawMutex, Option<PendingCalibration>> = Mutex::new(None);
+
+/// windows of a channel to sum up for a calibration step, taken by the stpm
+/// task
+#[derive(Clone, Copy)]
+pub struct MeasurementRequest {
+    pub channel: usize,
+    pub windows: usize,
+    /// measure the noise the no-load detection would zero
+    pub bypass_no_load: bool,
+}
+
+pub static MEASUREMENT_REQUEST: Signal<MeasurementRequest> = Signal::new();
+pub static MEASUREMENT_RESULT: Signal<RawSampleApp> = Signal::new();
+
+/// sums up the windows of a measurement in the stpm task
+pub struct Measurement {
+    request: MeasurementRequest,
+    /// the window running when the request came in is incomplete
+    started: bool,
+    collected: usize,
+    sum: RawSampleApp,
+}
+
+impl Measurement {
+    pub fn new(request: MeasurementRequest) -> Self {
+        Self {
+            request,
+            started: false,
+            collected: 0,
+            sum: Default::default(),
+        }
+    }
+
+    /// the readings of `channel` are not zeroed by the no-load detection
+    pub fn bypasses_no_load(&self, channel: usize) -> bool {
+        self.started && self.request.bypass_no_load && self.request.channel == channel
+    }
+
+    /// adds a finished window, returns the sum once all windows are in
+    pub fn add_window(&mut self, samples: &[RawSampleApp; 2]) -> Option<RawSampleApp> {
+        if !self.started {
+            self.started = true;
+            return None;
+        }
+        let sample = &samples[self.request.channel];
+        let sum = &mut self.sum;
+        sum.voltage_rms += sample.voltage_rms;
+        sum.voltage_rms_sq += sample.voltage_rms_sq;
+        sum.current_rms += sample.current_rms;
+        sum.current_rms_sq += sample.current_rms_sq;
+        sum.power_active += sample.power_active;
+        sum.power_reactive += sample.power_reactive;
+        sum.power_apparent += sample.power_apparent;
+        sum.no_load_active += sample.no_load_active;
+        sum.no_load_reactive += sample.no_load_reactive;
+        sum.no_load_current += sample.no_load_current;
+        sum.mapping_failed |= sample.mapping_failed;
+        sum.num_samples += sample.num_samples;
+        sum.rms_mode = sample.rms_mode;
+        self.collected += 1;
+        (self.collected >= self.request.windows).then_some(self.sum)
+    }
+}
+
+/// resistive reference load, values as shown by a reference meter
+#[derive(Deserialize, Clone)]
+pub struct ReferenceRequest {
+    /// 1 or 2
+    pub channel: usize,
+    /// volts
+    pub voltage: f32,
+    /// amps
+    pub current: f32,
+    /// watts
+    pub power: f32,
+    pub windows: Option<usize>,
+}
+
+/// inductive reference load, only the active power is needed
+#[derive(Deserialize, Clone)]
+pub struct PhaseRequest {
+    /// 1 or 2
+    pub channel: usize,
+    /// watts
+    pub power: f32,
+    pub windows: Option<usize>,
+}
+
+/// no load on the channel
+#[derive(Deserialize, Clone)]
+pub struct OffsetRequest {
+    /// 1 or 2
+    pub channel: usize,
+    pub windows: Option<usize>,
+}
+
+#[derive(Serialize, Default, Clone)]
+pub struct Readings {
+    pub voltage: f32,
+    pub current: f32,
+    pub power: f32,
+}
+
+#[derive(Serialize, Clone)]
+pub struct ReferenceReport {
+    /// averaged with the active calibration
+    pub measured: Readings,
+    /// relative errors in percent with the active / the new calibration
+    pub error_before: Readings,
+    pub error_after: Readings,
+    pub voltage_divider_factor: f32,
+    /// volts per amp of the current sensor
+    pub current_sensitivity: f32,
+}
+
+#[derive(Serialize, Clone)]
+pub struct PhaseReport {
+    /// averaged with the active calibration, in watts and var
+    pub power: f32,
+    pub reactive_power: f32,
+    /// relative error of the active power in percent
+    pub error_before: f32,
+    /// phase error of the sensor in degrees, positive if the current leads
+    pub phase_error: f32,
+    /// phase compensation in microseconds, active / new
+    pub compensation_before: f32,
+    pub compensation_after: f32,
+}
+
+#[derive(Serialize, Clone)]
+pub struct OffsetReport {
+    /// in amps, watts and var
+    pub current_offset: f32,
+    pub active_power_offset: f32,
+    pub reactive_power_offset: f32,
+}
+
+fn windows(requested: Option<usize>) -> Option<usize> {
+    match requested.unwrap_or(DEFAULT_WINDOWS) {
+        n @ 1..=MAX_WINDOWS => Some(n),
+        _ => None,
+    }
+}
+
+/// a calibrated reading as a float, None if it could not be computed
+fn reading<T: Into<i128>>(value: Option<T>, scale: f32) -> Result<f32, &'static str> {
+    Ok(value.ok_or("reading out of range")?.into() as f32 / scale)
+}
+
+/// relative error in percent
+fn error(measured: f32, reference: f32) -> f32 {
+    (measured - reference) / reference * 100.0
+}
+
+/// a calibration step and the config it was started with
+#[derive(Clone)]
+pub enum Step {
+    Reference(ReferenceRequest),
+    Phase(PhaseRequest),
+    Offset(OffsetRequest),
+}
+
+#[derive(Serialize, Clone)]
+#[serde(untagged)]
+pub enum Report {
+    Reference(ReferenceReport),
+    Phase(PhaseReport),
+    Offset(OffsetReport),
+}
+
+#[derive(Clone)]
+pub enum CalibrationStatus {
+    Idle,
+    Measuring {
+        step: Step,
+        calibration: CalibrationConfig,
+        stpm: StpmConfig,
+        deadline: Instant,
+    },
+    Done(Result<Report, &'static str>),
+}
+
+static STATUS: Mutex<CriticalSectionRawMutex, CalibrationStatus> = Mutex::new(CalibrationStatus::Idle);
+
+/// checks the request and starts its measurement, the result is picked up
+/// by `poll`. a running step is replaced.
+pub async fn start(step: Step, calibration: CalibrationConfig, stpm: StpmConfig) -> Result<(), &'static str> {
+    let request = match &step {
+        Step::Reference(request) => {
+            let positive = |value: f32| value.is_finite() && value > 0.0;
+            if !(positive(request.voltage) && positive(request.current) && positive(request.power)) {
+                return Err("reference values must be positive");
+            }
+            measurement(request.channel, request.windows, false)?
+        }
+        Step::Phase(request) => {
+            if !(request.power.is_finite() && request.power > 0.0) {
+                return Err("reference power must be positive");
+            }
+            measurement(request.channel, request.windows, false)?
+        }
+        // below the no-load threshold the readings would be zero
+        Step::Offset(request) => measurement(request.channel, request.windows, true)?,
+    };
+
+    let mut status = STATUS.lock().await;
+    MEASUREMENT_RESULT.reset();
+    MEASUREMENT_REQUEST.signal(request);
+    *status = CalibrationStatus::Measuring {
+        step,
+        calibration,
+        stpm,
+        // one more for the window that is skipped
+        deadline: Instant::now() + MAX_WINDOW_TIME * (request.windows as u32 + 1),
+    };
+    Ok(())
+}
+
+/// finishes the running step once its measurement is in, the result stays
+/// until the next step is started
+pub async fn poll() -> CalibrationStatus {
+    let mut status = STATUS.lock().await;
+    if let CalibrationStatus::Measuring {
+        step,
+        calibration,
+        stpm,
+        deadline,
+    } = &*status
+    {
+        if let Some(sum) = MEASUREMENT_RESULT.try_take() {
+            let result = finish(step, calibration, stpm, sum);
+            let report = match result {
+                Ok((report, pending)) => {
+                    *PENDING.lock().await = Some(pending);
+                    Ok(report)
+                }
+                Err(e) => Err(e),
+            };
+            *status = CalibrationStatus::Done(report);
+        } else if Instant::now() > *deadline {
+            *status = CalibrationStatus::Done(Err("no samples"));
+        }
+    }
+    status.clone()
+}
+
+fn measurement(channel: usize, windows: Option<usize>, bypass_no_load: bool) -> Result<MeasurementRequest, &'static str> {
+    if !(1..=2).contains(&channel) {
+        return Err("invalid channel");
+    }
+    Ok(MeasurementRequest {
+        channel: channel - 1,
+        windows: self::windows(windows).ok_or("invalid number of windows")?,
+        bypass_no_load,
+    })
+}
+
+fn finish(
+    step: &Step,
+    calibration: &CalibrationConfig,
+    stpm: &StpmConfig,
+    sum: RawSampleApp,
+) -> Result<(Report, PendingCalibration), &'static str> {
+    match step {
+        Step::Reference(request) => {
+            calibrate_reference(calibration, request, sum).map(|(report, pending)| (Report::Reference(report), pending))
+        }
+        Step::Phase(request) => {
+            calibrate_phase(calibration, stpm, request, sum).map(|(report, pending)| (Report::Phase(report), pending))
+        }
+        Step::Offset(request) => {
+            calibrate_offset(calibration, request, sum).map(|(report, pending)| (Report::Offset(report), pending))
+        }
+    }
+}
+
+/// averages the measured windows with `calibration`
+fn measure(calibration: &CalibrationConfig, channel: usize, sum: RawSampleApp) -> IntCalibratedSample {
+    let cal = conversion_parameters(calibration, channel).to_float_cal().to_int_cal();
+    cal.apply(sum, zcr::is_plausible)
+}
+
+/// step 1: scales the voltage divider and the current sensor so voltage and
+/// current match the reference
+fn calibrate_reference(
+    calibration: &CalibrationConfig,
+    request: &ReferenceRequest,
+    sum: RawSampleApp,
+) -> Result<(ReferenceReport, PendingCalibration), &'static str> {
+    let channel = request.channel - 1;
+
+    let sample = measure(calibration, channel, sum);
+    let measured = Readings {
+        voltage: reading(sample.voltage_rms, 1e3)?,
+        current: reading(sample.current_rms, 1e4)?,
+        power: reading(sample.power_active, 1e3)?,
+    };
+    if measured.voltage <= 0.0 || measured.current <= 0.0 {
+        return Err("no voltage or current measured");
+    }
+
+    let voltage_factor = request.voltage / measured.voltage;
+    let current_factor = request.current / measured.current;
+
+    let mut new_calibration = calibration.clone();
+    let new_channel = &mut new_calibration.channels[channel];
+    new_channel.voltage_divider_factor *= voltage_factor;
+    // the current reading is inversely proportional to the sensor output
+    new_channel.current_sensor = new_channel.current_sensor.scaled(1.0 / current_factor);
+    if !new_calibration.validate() {
+        return Err("resulting calibration is invalid");
+    }
+    let new_channel = &new_calibration.channels[channel];
+
+    let report = ReferenceReport {
+        error_before: Readings {
+            voltage: error(measured.voltage, request.voltage),
+            current: error(measured.current, request.current),
+            power: error(measured.power, request.power),
+        },
+        // voltage and current match by construction, the power error left is
+        // what the scaling can not fix (phase or reference mismatch)
+        error_after: Readings {
+            voltage: 0.0,
+            current: 0.0,
+            power: error(measured.power * voltage_factor * current_factor, request.power),
+        },
+        measured,
+        voltage_divider_factor: new_channel.voltage_divider_factor,
+        current_sensitivity: new_channel.current_sensor.volts_per_amp(),
+    };
+    let pending = PendingCalibration::Reference {
+        channel,
+        voltage_divider_factor: new_channel.voltage_divider_factor,
+        current_sensor: new_channel.current_sensor,
+    };
+
+    Ok((report, pending))
+}
+
+/// step 2: with an inductive load, a phase error of the sensor shows up as
+/// an active power error of about Q * error (in radians)
+fn calibrate_phase(
+    calibration: &CalibrationConfig,
+    stpm: &StpmConfig,
+    request: &PhaseRequest,
+    sum: RawSampleApp,
+) -> Result<(PhaseReport, PendingCalibration), &'static str> {
+    let channel = request.channel - 1;
+
+    let sample = measure(calibration, channel, sum);
+    let power = reading(sample.power_active, 1e3)?;
+    let reactive_power = reading(sample.power_reactive, 1e3)?;
+    if reactive_power < MIN_REACTIVE_RATIO * power && -reactive_power < MIN_REACTIVE_RATIO * power {
+        return Err("load is not inductive enough");
+    }
+
+    // P_measured = S cos(phi - e) ~ P + Q e, for a current leading by e
+    let phase_error_rad = (power - request.power) / reactive_power;
+    let line_frequency = line_frequency() as f32;
+    let error_us = phase_error_rad / (2.0 * core::f32::consts::PI * line_frequency) * 1e6;
+
+    let compensation_before = stpm.phase_compensation[channel].to_micros(line_frequency);
+    let compensation_after = compensation_before + error_us;
+    let range = StpmChannelConfiguration::PHASE_COMP_MIN_US..=StpmChannelConfiguration::PHASE_COMP_MAX_US;
+    if !range.contains(&compensation_after) {
+        return Err("phase compensation out of range");
+    }
+
+    let mut new_stpm = stpm.clone();
+    new_stpm.phase_compensation[channel] = PhaseCompensation::Micros(compensation_after);
+    if !new_stpm.validate(calibration) {
+        return Err("resulting config is invalid");
+    }
+
+    let report = PhaseReport {
+        power,
+        reactive_power,
+        error_before: error(power, request.power),
+        phase_error: phase_error_rad * 180.0 / core::f32::consts::PI,
+        compensation_before,
+        compensation_after,
+    };
+    let pending = PendingCalibration::Phase {
+        channel,
+        compensation: new_stpm.phase_compensation[channel],
+    };
+
+    Ok((report, pending))
+}
+
+/// measures the readings without load, they become the offsets
+fn calibrate_offset(
+    calibration: &CalibrationConfig,
+    request: &OffsetRequest,
+    sum: RawSampleApp,
+) -> Result<(OffsetReport, PendingCalibration), &'static str> {
+    let channel = request.channel - 1;
+
+    // measure without the old offsets
+    let mut new_calibration = calibration.clone();
+    let new_channel = &mut new_calibration.channels[channel];
+    new_channel.current_offset = 0.0;
+    new_channel.active_power_offset = 0.0;
+    new_channel.reactive_power_offset = 0.0;
+
+    let sample = measure(&new_calibration, channel, sum);
+    let report = OffsetReport {
+        current_offset: reading(sample.current_rms, 1e4)?,
+        active_power_offset: reading(sample.power_active, 1e3)?,
+        reactive_power_offset: reading(sample.power_reactive, 1e3)?,
+    };
+    if report.current_offset > MAX_OFFSET_CURRENT {
+        return Err("channel carries current");
+    }
+
+    let new_channel = &mut new_calibration.channels[channel];
+    new_channel.current_offset = report.current_offset;
+    new_channel.active_power_offset = report.active_power_offset;
+    new_channel.reactive_power_offset = report.reactive_power_offset;
+    if !new_calibration.validate() {
+        return Err("resulting calibration is invalid");
+    }
+    let pending = PendingCalibration::Offset {
+        channel,
+        current_offset: report.current_offset,
+        active_power_offset: report.active_power_offset,
+        reactive_power_offset: report.reactive_power_offset,
+    };
+
+    Ok((report, pending))
+}
@@ -0,0 +1,136 @@
+//! config structs as the unversioned firmware stored them, only read to
+//! convert an old EEPROM to the current layout
+
+use energy_core::storage::LegacyReader;
+use heapless::String;
+use serde::Deserialize;
+
+use super::{CalibrationChannelConfig, CalibrationConfig, CurrentSensor, MqttChannelEnables, MqttConfig, StpmConfig, WifiConfig};
+use crate::{config::server::ServerState, stpm::StpmCurrentGain};
+
+#[derive(Deserialize)]
+struct MqttChannelEnablesV0 {
+    frequency: bool,
+    voltage: bool,
+    current: bool,
+    active_power: bool,
+    reactive_power: bool,
+    energy: bool,
+}
+
+#[derive(Deserialize)]
+struct MqttConfigV0 {
+    broker_address: String<128>,
+    broker_port: u16,
+    mqtt_username: String<32>,
+    mqtt_password: String<32>,
+    mqtt_client_id: String<32>,
+    ha_unique_id: String<32>,
+    ha_discovery_prefix: String<32>,
+    ha_device_name: String<32>,
+    channel_names: [String<32>; 2],
+    channel_enable: [MqttChannelEnablesV0; 2],
+}
+
+#[derive(Deserialize)]
+struct StpmConfigV0 {
+    samples_stpm: usize,
+    current_gain: [StpmCurrentGain; 2],
+}
+
+#[derive(Deserialize)]
+struct CalibrationChannelConfigV0 {
+    voltage_divider_factor: f32,
+    current_shunt: f32,
+    current_gain: StpmCurrentGain,
+}
+
+#[derive(Deserialize)]
+struct CalibrationConfigV0 {
+    frequency_esp_adjust: f32,
+    frequency_stpm_adjust: f32,
+    channels: [CalibrationChannelConfigV0; 2],
+}
+
+impl From<MqttChannelEnablesV0> for MqttChannelEnables {
+    fn from(old: MqttChannelEnablesV0) -> Self {
+        Self {
+            frequency: old.frequency,
+            voltage: old.voltage,
+            current: old.current,
+            active_power: old.active_power,
+            reactive_power: old.reactive_power,
+            energy: old.energy,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<MqttConfigV0> for MqttConfig {
+    fn from(old: MqttConfigV0) -> Self {
+        let [enable1, enable2] = old.channel_enable;
+        Self {
+            broker_address: old.broker_address,
+            broker_port: old.broker_port,
+            mqtt_username: old.mqtt_username,
+            mqtt_password: old.mqtt_password,
+            mqtt_client_id: old.mqtt_client_id,
+            ha_unique_id: old.ha_unique_id,
+            ha_discovery_prefix: old.ha_discovery_prefix,
+            ha_device_name: old.ha_device_name,
+            channel_names: old.channel_names,
+            channel_enable: [enable1.into(), enable2.into()],
+            ..Default::default()
+        }
+    }
+}
+
+impl From<StpmConfigV0> for StpmConfig {
+    fn from(old: StpmConfigV0) -> Self {
+        Self {
+            samples_stpm: old.samples_stpm,
+            current_gain: old.current_gain,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<CalibrationConfigV0> for CalibrationConfig {
+    fn from(old: CalibrationConfigV0) -> Self {
+        Self {
+            frequency_esp_adjust: old.frequency_esp_adjust,
+            frequency_stpm_adjust: old.frequency_stpm_adjust,
+            channels: old.channels.map(|channel| CalibrationChannelConfig {
+                voltage_divider_factor: channel.voltage_divider_factor,
+                current_sensor: CurrentSensor::Shunt {
+                    resistance: channel.current_shunt,
+                },
+                current_gain: channel.current_gain,
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+/// converts the unversioned config, None if `buffer` does not hold one. like
+/// the old firmware, a config with any section missing is not used.
+pub fn read(buffer: &[u8]) -> Option<ServerState> {
+    let mut reader = LegacyReader::new(buffer);
+    let mqtt: MqttConfig = reader.read::<MqttConfigV0>()?.into();
+    let wifi = reader.read::<WifiConfig>()?;
+    let stpm: StpmConfig = reader.read::<StpmConfigV0>()?.into();
+    let calibration: CalibrationConfig = reader.read::<CalibrationConfigV0>()?.into();
+
+    // the old firmware only checked the mqtt config, the others fall back
+    // to their default
+    if !mqtt.validate() {
+        return None;
+    }
+    let calibration = if calibration.validate() { calibration } else { Default::default() };
+    Some(ServerState {
+        mqtt,
+        wifi,
+        stpm: if stpm.validate(&calibration) { stpm } else { Default::default() },
+        calibration,
+    })
+}
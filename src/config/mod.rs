@@ -1,13 +1,15 @@
 pub mod server;
 
 pub mod json_body;
+pub mod calibrate;
+mod legacy;
 mod structs;
 
-use crc::{Crc, CRC_32_ISCSI};
-use embassy_time::Timer;
-use embedded_hal_async::i2c::I2c;
+use embassy_time::Duration;
+use energy_core::storage::{self, read_memory, write_memory, AccumulatorError, SectionReader, SectionWriter};
 use esp_hal::{i2c::I2C, peripherals::I2C0};
 use esp_println::println;
+use serde::de::DeserializeOwned;
 pub use structs::*;
 
 mod button;
@@ -15,7 +17,7 @@ pub use button::*;
 
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex, signal};
 
-use crate::config::server::ServerState;
+use crate::{config::server::ServerState, stpm::EnergyTotals};
 
 type Signal<T> = signal::Signal<CriticalSectionRawMutex, T>;
 
@@ -23,136 +25,128 @@ pub static CONFIG_MQTT: Signal<MqttConfig> = Signal::new();
 pub static CONFIG_WIFI: Signal<WifiConfig> = Signal::new();
 pub static CONFIG_STPM: Signal<StpmConfig> = Signal::new();
 pub static CONFIG_CALIBRATION: Signal<CalibrationConfig> = Signal::new();
+/// same as CONFIG_CALIBRATION, for the stpm task
+pub static CONFIG_CALIBRATION_STPM: Signal<CalibrationConfig> = Signal::new();
 pub static RESET_ACCUMULATOR: Signal<()> = Signal::new();
 
 type AppI2C = I2C<'static, I2C0>;
 
 static EEPROM_I2C: Mutex<CriticalSectionRawMutex, Option<AppI2C>> = Mutex::new(None);
 
-/// returns force_ap -> wifi config was not loaded, enable AP in any case
 pub async fn run_config(i2c: AppI2C) {
     *EEPROM_I2C.lock().await = Some(i2c);
 
-    // if everything goes well, do nothing
-    if read_config().await == Ok(()) {
-        println!("loaded config from EEPROM");
-        return;
+    let mut buffer = [0u8; 4096];
+    let eeprom_read = read_eeprom(&mut buffer).await.is_ok();
+    let mut reader = if eeprom_read { SectionReader::new(&buffer) } else { None };
+    let mut migrated = None;
+
+    match &reader {
+        Some(r) if r.version() == CONFIG_VERSION => println!("loading config from EEPROM"),
+        Some(r) => {
+            // migrations from older versions go here
+            println!("unknown config version {}, using default config", r.version());
+            reader = None;
+        }
+        None if eeprom_read => {
+            migrated = legacy::read(&buffer);
+            match migrated {
+                Some(_) => println!("converting unversioned config from EEPROM"),
+                None => println!("no config in EEPROM, using default config"),
+            }
+        }
+        None => println!("no config in EEPROM, using default config"),
     }
 
-    println!("using default config");
+    let converted = migrated.is_some();
+    let (state, force_ap) = match migrated {
+        Some(state) => (state, false),
+        None => {
+            let mqtt = read_section(&mut reader, "mqtt", MqttConfig::validate);
+            let wifi = read_section(&mut reader, "wifi", |_| true);
+            let stpm = read_section(&mut reader, "stpm", StpmConfig::validate);
+            let calibration = read_section(&mut reader, "calibration", CalibrationConfig::validate);
+
+            // without the wifi config, the device is only reachable through the AP
+            let force_ap = wifi.is_none();
+            let state = ServerState {
+                mqtt: mqtt.unwrap_or_default(),
+                wifi: wifi.unwrap_or_default(),
+                stpm: stpm.unwrap_or_default(),
+                calibration: calibration.unwrap_or_default(),
+            };
+            (state, force_ap)
+        }
+    };
+
+    CONFIG_MQTT.signal(state.mqtt.clone());
+    CONFIG_WIFI.signal(state.wifi.clone());
+    CONFIG_STPM.signal(state.stpm.clone());
+    CONFIG_CALIBRATION.signal(state.calibration.clone());
+    CONFIG_CALIBRATION_STPM.signal(state.calibration.clone());
 
-    server::STATE.lock().await.replace(ServerState::default());
+    server::STATE.lock().await.replace(state);
 
-    CONFIG_MQTT.signal(Default::default());
-    CONFIG_WIFI.signal(Default::default());
-    CONFIG_STPM.signal(Default::default());
-    CONFIG_CALIBRATION.signal(Default::default());
+    // store the converted config in the current layout right away
+    if converted && save_config().await.is_none() {
+        println!("error saving the converted config");
+    }
 
-    set_ap(true);
+    if force_ap {
+        set_ap(true);
+    }
 }
 
-const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+/// version of the config layout in the EEPROM, increase when a config struct
+/// changes. sections that can't be read fall back to their default.
+const CONFIG_VERSION: u16 = 1;
 const EEPROM_ADDR: u8 = 0b101_0000;
 const FRAM_ADDR: u8 = 0b1010_010;
 
-async fn read_config() -> Result<(), ()> {
-    let mut buffer = [0u8; 4096];
-
-    // read into buffer in loop, i2c driver supports max read of 32 bytes
-    const PAGE_SIZE: usize = 16;
+/// reads the next section, None if it is missing or invalid
+fn read_section<T: DeserializeOwned>(
+    reader: &mut Option<SectionReader>,
+    name: &str,
+    validate: fn(&T) -> bool,
+) -> Option<T> {
+    let reader = reader.as_mut()?;
+    let Some(value) = reader.read::<T>() else {
+        println!("error deserializing {name}, using default");
+        return None;
+    };
+    if !validate(&value) {
+        println!("error validating {name}, using default");
+        return None;
+    }
+    Some(value)
+}
 
+async fn read_eeprom(buffer: &mut [u8; 4096]) -> Result<(), ()> {
     let mut i2c = EEPROM_I2C.lock().await;
     let i2c = i2c.as_mut().unwrap();
 
-    // read with address setup first
     // we have a 32K EEPROM -> two address bytes
-    let buffer_write = 0u16.to_be_bytes();
-    let res = i2c
-        .write_read(EEPROM_ADDR, &buffer_write, &mut buffer[..PAGE_SIZE])
-        .await;
-    if let Err(e) = res {
+    if let Err(e) = read_memory(i2c, EEPROM_ADDR, 0, buffer).await {
         println!("error reading config from i2c {e:?}");
         return Err(());
     }
 
-    // read remaining buffer without address setup
-    let mut addr = PAGE_SIZE;
-    while addr < buffer.len() {
-        let res = i2c
-            .read(EEPROM_ADDR, &mut buffer[addr..addr + PAGE_SIZE])
-            .await;
-        if let Err(e) = res {
-            println!("error reading config from i2c {e:?}");
-            return Err(());
-        }
-        addr += PAGE_SIZE;
-    }
-
-    let Ok((mqtt, buffer)) = postcard::take_from_bytes_crc32::<MqttConfig>(&buffer, CRC.digest())
-    else {
-        println!("error deserializing mqtt");
-        return Err(());
-    };
-
-    let Ok((wifi, buffer)) = postcard::take_from_bytes_crc32::<WifiConfig>(&buffer, CRC.digest())
-    else {
-        println!("error deserializing wifi");
-        return Err(());
-    };
-
-    let Ok((stpm, buffer)) = postcard::take_from_bytes_crc32::<StpmConfig>(&buffer, CRC.digest())
-    else {
-        println!("error deserializing wifi");
-        return Err(());
-    };
-
-    let Ok((calibration, _)) =
-        postcard::take_from_bytes_crc32::<CalibrationConfig>(&buffer, CRC.digest())
-    else {
-        println!("error deserializing wifi");
-        return Err(());
-    };
-
-    if !mqtt.validate() {
-        println!("error validating mqtt");
-        return Err(());
-    }
-
-    CONFIG_MQTT.signal(mqtt.clone());
-    CONFIG_WIFI.signal(wifi.clone());
-    CONFIG_STPM.signal(stpm.clone());
-    CONFIG_CALIBRATION.signal(calibration.clone());
-
-    server::STATE.lock().await.replace(ServerState{
-        mqtt,
-        wifi,
-        stpm,
-        calibration,
-    });
-
     Ok(())
 }
 
 pub async fn save_config() -> Option<()> {
-    // start at byte two -> keep two address bytes for i2c eeprom
-    let mut buffer = [0u8; 4096 + 2];
-    let mut n = 2;
+    let mut buffer = [0u8; 4096];
+    let n;
 
     {
         let state = server::STATE.lock().await;
         let state = state.as_ref().unwrap();
-        n += postcard::to_slice_crc32(&state.mqtt, &mut buffer[n..], CRC.digest())
-            .ok()?
-            .len();
-        n += postcard::to_slice_crc32(&state.wifi, &mut buffer[n..], CRC.digest())
-            .ok()?
-            .len();
-        n += postcard::to_slice_crc32(&state.stpm, &mut buffer[n..], CRC.digest())
-            .ok()?
-            .len();
-        n += postcard::to_slice_crc32(&state.calibration, &mut buffer[n..], CRC.digest())
-            .ok()?
-            .len();
+        let mut writer = SectionWriter::new(&mut buffer, CONFIG_VERSION)?;
+        writer.write(&state.mqtt)?;
+        writer.write(&state.wifi)?;
+        writer.write(&state.stpm)?;
+        writer.write(&state.calibration)?;
+        n = writer.finish();
     }
 
     let mut i2c = EEPROM_I2C.lock().await;
@@ -160,32 +154,10 @@ pub async fn save_config() -> Option<()> {
 
     println!("start saving to EEPROM, {n} bytes");
 
-    // ESP32 i2c can only transfer 31 bytes at once with the current i2c implementation
-    // if this restriction is lifted, increase page size to 32 (eeprom)
-    const PAGE_SIZE: usize = 16;
-
-    let mut buffer = &mut buffer[..n];
-    let mut addr = 0u16;
-
-    while buffer.len() > 2 {
-        println!("writing data at addr {addr}");
-
-        // update address in buffer
-        let addr_bytes = addr.to_be_bytes();
-        buffer[..2].copy_from_slice(&addr_bytes);
-
-        // send i2c
-        let len = buffer.len().min(PAGE_SIZE + 2);
-        if let Err(e) = i2c.write(EEPROM_ADDR, &buffer[..len]).await {
-            println!("error writing to i2c EEPROM {e:?}");
-            return None;
-        }
-
-        // wait for write to finish internally
-        Timer::after_millis(5).await;
-
-        addr += PAGE_SIZE as u16;
-        buffer = &mut buffer[len-2..];
+    // wait 5ms per page for the write to finish internally
+    if let Err(e) = write_memory(i2c, EEPROM_ADDR, 0, &buffer[..n], Duration::from_millis(5)).await {
+        println!("error writing to i2c EEPROM {e:?}");
+        return None;
     }
 
     println!("end saving to EEPROM");
@@ -193,50 +165,28 @@ pub async fn save_config() -> Option<()> {
     Some(())
 }
 
-pub async fn read_accumulator() -> Result<[i64; 2], ()> {
+/// Ok(None) if the FRAM was never written, `legacy_lsb` converts the totals
+/// of the old firmware, see `storage::read_accumulator`
+pub async fn read_accumulator(legacy_lsb: [i64; 2]) -> Result<Option<EnergyTotals>, ()> {
     let mut i2c = EEPROM_I2C.lock().await;
     let i2c = i2c.as_mut().unwrap();
 
-    // read with address setup first
     // we have a 8K FRAM -> two address bytes
-    let buffer_write = 0u16.to_be_bytes();
-    let mut buffer_read = [0u8; 20];
-    let res = i2c.write_read(FRAM_ADDR, &buffer_write, &mut buffer_read).await;
-
-    if let Err(e) = res {
-        println!("error reading accumulator from i2c {e:?}");
-        return Err(());
-    }
-
-    match postcard::from_bytes_crc32::<[i64; 2]>(&buffer_read, CRC.digest()) {
-        Ok(acc) => Ok(acc),
-        Err(_) => {
-            println!("error deserializing accumulator");
+    match storage::read_accumulator(i2c, FRAM_ADDR, legacy_lsb).await {
+        Ok(totals) => Ok(Some(totals)),
+        Err(AccumulatorError::Empty) => Ok(None),
+        Err(e) => {
+            println!("error reading accumulator {e:?}");
             Err(())
         }
     }
 }
 
-pub async fn write_accumulator(accumulator: &[i64; 2]) -> Result<(), ()> {
+pub async fn write_accumulator(accumulator: &EnergyTotals) -> Result<(), ()> {
     let mut i2c = EEPROM_I2C.lock().await;
     let i2c = i2c.as_mut().unwrap();
 
-    let mut buffer = [0u8; 22];
-    
-    match postcard::to_slice_crc32(accumulator, &mut buffer[2..], CRC.digest()) {
-        Ok(_) => {},
-        Err(_) => {
-            println!("error serializing accumulator");
-            return Err(());
-        }
-    }
-
-    let res = i2c.write(FRAM_ADDR, &buffer).await;
-
-    if let Err(e) = res {
-        println!("error writing accumulator to i2c {e:?}");
-        return Err(());
-    }
-
-    Ok(())
+    storage::write_accumulator(i2c, FRAM_ADDR, accumulator)
+        .await
+        .map_err(|e| println!("error writing accumulator {e:?}"))
 }
@@ -2,6 +2,7 @@ use embassy_futures::select::{select, select3, Either, Either3};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
 use embassy_time::Duration;
 use esp_println::println;
+use serde::Deserialize;
 use picoserve::{
     response::{IntoResponse, Json, StatusCode},
     routing::{get, post},
@@ -11,11 +12,20 @@ use static_cell::make_static;
 
 use crate::{
     config::{CONFIG_SERVER_ENABLE, RESET_ACCUMULATOR},
+    stpm::{
+        calibration::conversion_parameters,
+        harmonics::{CAPTURE_REQUEST, LATEST_HARMONICS},
+        LATEST_SAMPLES,
+    },
     wifi::{StackAp, StackSta},
+    zcr,
 };
 
 use super::{
-    json_body::JsonBody, save_config, CalibrationConfig, MqttConfig, StpmConfig, WifiConfig, CONFIG_CALIBRATION, CONFIG_MQTT, CONFIG_STPM, CONFIG_WIFI
+    calibrate::{
+        self, CalibrationStatus, OffsetRequest, PendingCalibration, PhaseRequest, ReferenceRequest, Step, PENDING,
+    },
+    json_body::JsonBody, save_config, CalibrationConfig, MqttConfig, StpmConfig, WifiConfig, CONFIG_CALIBRATION, CONFIG_CALIBRATION_STPM, CONFIG_MQTT, CONFIG_STPM, CONFIG_WIFI
 };
 
 const KEEP_PASSWORD: &str = "--keep--";
@@ -61,6 +71,14 @@ pub async fn run_config_server(stack_sta: &'static StackSta, stack_ap: Option<&'
             )
             .route("/save", post(post_save_config))
             .route("/reset_accumulator", post(post_reset_accumulator))
+            .route("/samples.json", get(get_samples))
+            .route("/capture", post(post_capture))
+            .route("/harmonics.json", get(get_harmonics))
+            .route("/calibrate", post(post_calibrate))
+            .route("/calibrate_phase", post(post_calibrate_phase))
+            .route("/calibrate_offset", post(post_calibrate_offset))
+            .route("/calibrate_report", get(get_calibrate_report))
+            .route("/calibrate_apply", post(post_calibrate_apply))
     }
 
     let app = make_static!(make_app());
@@ -200,11 +218,23 @@ async fn get_config_calibration() -> impl IntoResponse {
 async fn post_config_calibration(
     JsonBody(new_config): JsonBody<CalibrationConfig>,
 ) -> impl IntoResponse {
+    if !new_config.validate() {
+        return (StatusCode::BAD_REQUEST, "config validation failed");
+    }
+
     let mut state = STATE.lock().await;
     let state = state.as_mut().unwrap();
 
+    // the stpm thresholds are converted with the calibration
+    if !state.stpm.validate(&new_config) {
+        return (StatusCode::BAD_REQUEST, "stpm thresholds out of range");
+    }
+
     state.calibration = new_config.clone();
+    CONFIG_CALIBRATION_STPM.signal(new_config.clone());
     CONFIG_CALIBRATION.signal(new_config);
+
+    (StatusCode::OK, "OK")
 }
 
 // -----------------------------------------------------------------------------
@@ -220,8 +250,14 @@ async fn post_config_stpm(JsonBody(new_config): JsonBody<StpmConfig>) -> impl In
     let mut state = STATE.lock().await;
     let state = state.as_mut().unwrap();
 
+    if !new_config.validate(&state.calibration) {
+        return (StatusCode::BAD_REQUEST, "config validation failed");
+    }
+
     state.stpm = new_config.clone();
     CONFIG_STPM.signal(new_config);
+
+    (StatusCode::OK, "OK")
 }
 
 // -----------------------------------------------------------------------------
@@ -238,3 +274,111 @@ async fn post_reset_accumulator() -> impl IntoResponse {
     RESET_ACCUMULATOR.signal(());
     (StatusCode::OK, "OK")
 }
+
+// -----------------------------------------------------------------------------
+
+async fn get_samples() -> impl IntoResponse {
+    let Some(samples) = LATEST_SAMPLES.lock(|latest| latest.get()) else {
+        return Err((StatusCode::SERVICE_UNAVAILABLE, "no samples yet"));
+    };
+
+    let state = STATE.lock().await;
+    let state = state.as_ref().unwrap();
+
+    let samples: [_; 2] = core::array::from_fn(|i| {
+        conversion_parameters(&state.calibration, i)
+            .to_float_cal()
+            .to_int_cal()
+            .apply(samples[i], zcr::is_plausible)
+    });
+    Ok(Json(samples))
+}
+
+// -----------------------------------------------------------------------------
+
+#[derive(Deserialize)]
+struct CaptureRequest {
+    /// 1 or 2
+    channel: usize,
+}
+
+async fn post_capture(JsonBody(request): JsonBody<CaptureRequest>) -> impl IntoResponse {
+    if !(1..=2).contains(&request.channel) {
+        return (StatusCode::BAD_REQUEST, "invalid channel");
+    }
+    CAPTURE_REQUEST.signal(request.channel - 1);
+    (StatusCode::OK, "OK")
+}
+
+async fn get_harmonics() -> impl IntoResponse {
+    Json(LATEST_HARMONICS.lock(|latest| latest.get()))
+}
+
+// -----------------------------------------------------------------------------
+
+/// starts a calibration step with a snapshot of the config, the report is
+/// polled at `/calibrate_report`
+async fn start_calibration(step: Step) -> impl IntoResponse {
+    let (calibration, stpm) = {
+        let state = STATE.lock().await;
+        let state = state.as_ref().unwrap();
+        (state.calibration.clone(), state.stpm.clone())
+    };
+    match calibrate::start(step, calibration, stpm).await {
+        Ok(()) => (StatusCode::ACCEPTED, "measuring"),
+        Err(e) => (StatusCode::BAD_REQUEST, e),
+    }
+}
+
+async fn post_calibrate(JsonBody(request): JsonBody<ReferenceRequest>) -> impl IntoResponse {
+    start_calibration(Step::Reference(request)).await
+}
+
+async fn post_calibrate_phase(JsonBody(request): JsonBody<PhaseRequest>) -> impl IntoResponse {
+    start_calibration(Step::Phase(request)).await
+}
+
+async fn post_calibrate_offset(JsonBody(request): JsonBody<OffsetRequest>) -> impl IntoResponse {
+    start_calibration(Step::Offset(request)).await
+}
+
+async fn get_calibrate_report() -> impl IntoResponse {
+    match calibrate::poll().await {
+        CalibrationStatus::Idle => Err((StatusCode::NOT_FOUND, "no calibration started")),
+        CalibrationStatus::Measuring { .. } => Err((StatusCode::ACCEPTED, "measuring")),
+        CalibrationStatus::Done(Ok(report)) => Ok(Json(report)),
+        CalibrationStatus::Done(Err(e)) => Err((StatusCode::BAD_REQUEST, e)),
+    }
+}
+
+async fn post_calibrate_apply() -> impl IntoResponse {
+    let Some(pending) = PENDING.lock().await.take() else {
+        return (StatusCode::BAD_REQUEST, "no calibration to apply");
+    };
+
+    let mut state = STATE.lock().await;
+    let state = state.as_mut().unwrap();
+
+    // only the calibrated values change, edits made during the measurement
+    // are kept
+    let mut calibration = state.calibration.clone();
+    let mut stpm = state.stpm.clone();
+    pending.apply(&mut calibration, &mut stpm);
+    if !calibration.validate() || !stpm.validate(&calibration) {
+        return (StatusCode::BAD_REQUEST, "resulting config is invalid");
+    }
+
+    match pending {
+        PendingCalibration::Reference { .. } | PendingCalibration::Offset { .. } => {
+            state.calibration = calibration.clone();
+            CONFIG_CALIBRATION_STPM.signal(calibration.clone());
+            CONFIG_CALIBRATION.signal(calibration);
+        }
+        PendingCalibration::Phase { .. } => {
+            state.stpm = stpm.clone();
+            CONFIG_STPM.signal(stpm);
+        }
+    }
+
+    (StatusCode::OK, "OK")
+}
@@ -1,7 +1,9 @@
 use heapless::{String, Vec};
 use serde::{Deserialize, Serialize};
 
-use crate::stpm::StpmCurrentGain;
+pub use energy_core::stpm::{calibration::RmsMode, mapping::VoltageMapping};
+
+use crate::stpm::{calibration::conversion_parameters, harmonics, StpmChannelConfiguration, StpmCurrentGain, StpmInterrupts, STPM_UART_BAUD, STPM_UART_BAUD_RANGE};
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct MqttChannelEnables {
@@ -10,7 +12,21 @@ pub struct MqttChannelEnables {
     pub current: bool,
     pub active_power: bool,
     pub reactive_power: bool,
+    pub apparent_power: bool,
+    pub power_factor: bool,
+    pub fundamental_power: bool,
+    pub harmonic_power: bool,
     pub energy: bool,
+    pub import_export_energy: bool,
+    pub fundamental_energy: bool,
+    pub reactive_energy: bool,
+    pub apparent_energy: bool,
+    pub overcurrent: bool,
+    pub phase_angle: bool,
+    pub harmonics: bool,
+    pub current_gain: bool,
+    // smallest / largest voltage, current and active power within a sample
+    pub min_max: bool,
 }
 
 impl Default for MqttChannelEnables {
@@ -21,11 +37,34 @@ impl Default for MqttChannelEnables {
             current: false,
             active_power: true,
             reactive_power: false,
+            apparent_power: false,
+            power_factor: false,
+            fundamental_power: false,
+            harmonic_power: false,
             energy: false,
+            import_export_energy: false,
+            fundamental_energy: false,
+            reactive_energy: false,
+            apparent_energy: false,
+            overcurrent: false,
+            phase_angle: false,
+            harmonics: false,
+            current_gain: false,
+            min_max: false,
         }
     }
 }
 
+/// values of the combined channel, the sum of both channels
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct MqttTotalEnables {
+    pub active_power: bool,
+    pub reactive_power: bool,
+    pub apparent_power: bool,
+    pub energy: bool,
+    pub import_export_energy: bool,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct MqttConfig {
     // tcp
@@ -41,6 +80,8 @@ pub struct MqttConfig {
     pub ha_device_name: String<32>,
     pub channel_names: [String<32>; 2],
     pub channel_enable: [MqttChannelEnables; 2],
+    pub total_name: String<32>,
+    pub total_enable: MqttTotalEnables,
 }
 
 impl MqttConfig {
@@ -93,6 +134,8 @@ impl Default for MqttConfig {
                 String::try_from("Channel 2").unwrap(),
             ],
             channel_enable: Default::default(),
+            total_name: String::try_from("Total").unwrap(),
+            total_enable: Default::default(),
         }
     }
 }
@@ -126,12 +169,164 @@ impl Default for WifiConfig {
     }
 }
 
+/// phase compensation for the current sensor, positive if the current leads
+/// the voltage (usual for current transformers)
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+pub enum PhaseCompensation {
+    #[default]
+    None,
+    Degrees(f32),
+    Micros(f32),
+}
+
+impl PhaseCompensation {
+    pub fn to_micros(&self, line_frequency: f32) -> f32 {
+        match *self {
+            PhaseCompensation::None => 0.0,
+            PhaseCompensation::Degrees(degrees) => degrees / 360.0 / line_frequency * 1e6,
+            PhaseCompensation::Micros(micros) => micros,
+        }
+    }
+}
+
+/// below these values a channel reads zero and its energy counters stop,
+/// None = disabled
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+pub struct NoLoadThreshold {
+    // in amps, also zeroes all powers
+    pub current: Option<f32>,
+    // in watts
+    pub active_power: Option<f32>,
+    // in var
+    pub reactive_power: Option<f32>,
+}
+
+/// nominal line frequency
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+pub enum LineFrequency {
+    #[default]
+    F50,
+    F60,
+    /// pick 50 or 60 Hz from the ZCR measurement at startup
+    Auto,
+}
+
+impl LineFrequency {
+    /// all line frequencies in Hz this setting might end up with
+    pub fn candidates(&self) -> &'static [f32] {
+        match self {
+            LineFrequency::F50 => &[50.0],
+            LineFrequency::F60 => &[60.0],
+            LineFrequency::Auto => &[50.0, 60.0],
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct StpmConfig {
     // how many samples to average
     pub samples_stpm: usize,
-    // channel current gain
+    // channel current gain, start value if auto ranging is enabled
     pub current_gain: [StpmCurrentGain; 2],
+    // switch the current gain at run time depending on the current
+    pub auto_current_gain: [bool; 2],
+    // voltage sag / swell event thresholds in volts, None = disabled
+    pub voltage_sag_threshold: [Option<f32>; 2],
+    pub voltage_swell_threshold: [Option<f32>; 2],
+    // overcurrent (current swell) threshold in amps, None = disabled
+    pub current_swell_threshold: [Option<f32>; 2],
+    // inrush event threshold of the momentary active power in watts, None =
+    // disabled
+    pub inrush_threshold: [Option<f32>; 2],
+    // suppresses the noise without load
+    pub no_load_threshold: [NoLoadThreshold; 2],
+    // which chip status bits are reported as interrupts
+    pub interrupts: StpmInterrupts,
+    // seconds between waveform captures for the harmonic analysis, at least
+    // harmonics::MIN_INTERVAL_SECS, None = only on request
+    pub harmonics_interval: Option<u32>,
+    // per channel phase compensation
+    pub phase_compensation: [PhaseCompensation; 2],
+    // nominal line frequency
+    pub line_frequency: LineFrequency,
+    // averaging of voltage and current RMS over a window
+    pub rms_mode: RmsMode,
+    // per channel voltage reference, for split-phase installations
+    pub voltage_mapping: [VoltageMapping; 2],
+    // baud rate when the chip is connected by UART, ignored for SPI
+    pub uart_baud_rate: u32,
+}
+
+/// sag / swell thresholds are compared against the upper 10 bits of the
+/// voltage RMS, 0x3ff disables swell detection
+const SAG_THRESHOLD_MAX: u64 = 0x3ff;
+const SWELL_THRESHOLD_MAX: u64 = 0x3fe;
+/// the no-load thresholds are raw values at gain 16, the chip reads up to the
+/// full scale at gain 2 (17 bit current RMS, 29 bit signed powers)
+const NO_LOAD_CURRENT_MAX: u64 = (1 << 17) * 8;
+const NO_LOAD_POWER_MAX: u64 = (1 << 28) * 8;
+
+/// true for a disabled threshold or a finite, non-negative one that is at
+/// most `max` as register value
+fn threshold_fits(threshold: Option<f32>, max: u64, to_raw: impl Fn(f32) -> u64) -> bool {
+    match threshold {
+        Some(value) => value.is_finite() && value >= 0.0 && to_raw(value) <= max,
+        None => true,
+    }
+}
+
+impl StpmConfig {
+    /// the thresholds are checked against the registers at `calibration`
+    pub fn validate(&self, calibration: &CalibrationConfig) -> bool {
+        // the compensation has to be possible at every line frequency the
+        // setting might resolve to
+        let range = StpmChannelConfiguration::PHASE_COMP_MIN_US..=StpmChannelConfiguration::PHASE_COMP_MAX_US;
+        for comp in self.phase_compensation {
+            for &line_frequency in self.line_frequency.candidates() {
+                if !range.contains(&comp.to_micros(line_frequency)) {
+                    return false;
+                }
+            }
+        }
+        if self.samples_stpm < 1 {
+            return false;
+        }
+        if self.inrush_threshold.iter().flatten().any(|watts| *watts <= 0.0) {
+            return false;
+        }
+        if self.voltage_mapping.iter().filter_map(|m| m.voltage_channel).any(|ch| !(1..=2).contains(&ch)) {
+            return false;
+        }
+        if !STPM_UART_BAUD_RANGE.contains(&self.uart_baud_rate) {
+            return false;
+        }
+        if self.harmonics_interval.is_some_and(|secs| secs < harmonics::MIN_INTERVAL_SECS) {
+            return false;
+        }
+        for i in 0..2 {
+            let float_cal = conversion_parameters(calibration, i).to_float_cal();
+            let voltage = |volts| float_cal.voltage_to_raw(volts) as u64 >> 5;
+            // with auto ranging the gain drops to 2 before the current reaches
+            // the threshold
+            let gain = if self.auto_current_gain[i] { StpmCurrentGain::X2 } else { self.current_gain[i] };
+            let anti_gain = gain.anti_gain() as u64;
+            let current_swell = |amps| (float_cal.current_to_raw(amps) as u64 / anti_gain) >> 7;
+            let current = |amps| float_cal.current_to_raw(amps) as u64;
+            let power = |watts| float_cal.power_to_raw(watts) as u64;
+
+            let no_load = &self.no_load_threshold[i];
+            if !(threshold_fits(self.voltage_sag_threshold[i], SAG_THRESHOLD_MAX, voltage)
+                && threshold_fits(self.voltage_swell_threshold[i], SWELL_THRESHOLD_MAX, voltage)
+                && threshold_fits(self.current_swell_threshold[i], SWELL_THRESHOLD_MAX, current_swell)
+                && threshold_fits(no_load.current, NO_LOAD_CURRENT_MAX, current)
+                && threshold_fits(no_load.active_power, NO_LOAD_POWER_MAX, power)
+                && threshold_fits(no_load.reactive_power, NO_LOAD_POWER_MAX, power))
+            {
+                return false;
+            }
+        }
+        true
+    }
 }
 
 impl Default for StpmConfig {
@@ -139,23 +334,105 @@ impl Default for StpmConfig {
         Self {
             samples_stpm: 20,
             current_gain: [StpmCurrentGain::X2; 2],
+            auto_current_gain: [false; 2],
+            voltage_sag_threshold: [None; 2],
+            voltage_swell_threshold: [None; 2],
+            current_swell_threshold: [None; 2],
+            inrush_threshold: [None; 2],
+            no_load_threshold: Default::default(),
+            interrupts: Default::default(),
+            harmonics_interval: None,
+            phase_compensation: Default::default(),
+            line_frequency: Default::default(),
+            rms_mode: Default::default(),
+            voltage_mapping: Default::default(),
+            uart_baud_rate: STPM_UART_BAUD,
+        }
+    }
+}
+
+/// sensor on the current input of a channel
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum CurrentSensor {
+    /// resistance in ohms
+    Shunt { resistance: f32 },
+    /// turns ratio (e.g. 2000 for 100 A : 50 mA) and burden resistor in ohms
+    CurrentTransformer { ratio: f32, burden: f32 },
+    /// output in volts per amp at 50 Hz, enables the integrator of the chip
+    Rogowski { sensitivity: f32 },
+}
+
+impl CurrentSensor {
+    /// voltage at the chip input per amp of primary current, at 50 Hz for
+    /// Rogowski coils
+    pub fn volts_per_amp(&self) -> f32 {
+        match *self {
+            CurrentSensor::Shunt { resistance } => resistance,
+            CurrentSensor::CurrentTransformer { ratio, burden } => burden / ratio,
+            CurrentSensor::Rogowski { sensitivity } => sensitivity,
+        }
+    }
+
+    /// the same sensor with `factor` times the output
+    pub fn scaled(&self, factor: f32) -> Self {
+        match *self {
+            CurrentSensor::Shunt { resistance } => CurrentSensor::Shunt {
+                resistance: resistance * factor,
+            },
+            CurrentSensor::CurrentTransformer { ratio, burden } => CurrentSensor::CurrentTransformer {
+                ratio,
+                burden: burden * factor,
+            },
+            CurrentSensor::Rogowski { sensitivity } => CurrentSensor::Rogowski {
+                sensitivity: sensitivity * factor,
+            },
+        }
+    }
+
+    /// the output of a Rogowski coil is the derivative of the current
+    pub fn needs_integrator(&self) -> bool {
+        matches!(self, CurrentSensor::Rogowski { .. })
+    }
+
+    pub fn validate(&self) -> bool {
+        let positive = |value: f32| value.is_finite() && value > 0.0;
+        match *self {
+            CurrentSensor::Shunt { resistance } => positive(resistance),
+            // a ratio below 1 is a voltage transformer
+            CurrentSensor::CurrentTransformer { ratio, burden } => positive(burden) && ratio.is_finite() && ratio >= 1.0,
+            CurrentSensor::Rogowski { sensitivity } => positive(sensitivity),
         }
     }
 }
 
+impl Default for CurrentSensor {
+    fn default() -> Self {
+        CurrentSensor::Shunt { resistance: 0.005 }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct CalibrationChannelConfig {
     pub voltage_divider_factor: f32,
-    pub current_shunt: f32,
+    pub current_sensor: CurrentSensor,
     pub current_gain: StpmCurrentGain,
+    // readings without load, removed from every sample. the current offset
+    // (amps) is noise and removed in quadrature, the powers (watts / var) are
+    // subtracted
+    pub current_offset: f32,
+    pub active_power_offset: f32,
+    pub reactive_power_offset: f32,
 }
 
 impl Default for CalibrationChannelConfig {
     fn default() -> Self {
         Self {
             voltage_divider_factor: 1700.0,
-            current_shunt: 0.005,
+            current_sensor: Default::default(),
             current_gain: StpmCurrentGain::X2,
+            current_offset: 0.0,
+            active_power_offset: 0.0,
+            reactive_power_offset: 0.0,
         }
     }
 }
@@ -167,6 +444,31 @@ pub struct CalibrationConfig {
     pub channels: [CalibrationChannelConfig; 2],
 }
 
+impl CalibrationConfig {
+    pub fn validate(&self) -> bool {
+        // the oscillators are crystals, anything far off is a typo
+        let adjust = 0.9..=1.1;
+        if !adjust.contains(&self.frequency_esp_adjust) || !adjust.contains(&self.frequency_stpm_adjust) {
+            return false;
+        }
+        for channel in &self.channels {
+            if !(channel.voltage_divider_factor.is_finite() && channel.voltage_divider_factor >= 1.0) {
+                return false;
+            }
+            if !channel.current_sensor.validate() {
+                return false;
+            }
+            if !(channel.current_offset.is_finite() && channel.current_offset >= 0.0) {
+                return false;
+            }
+            if !(channel.active_power_offset.is_finite() && channel.reactive_power_offset.is_finite()) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
 impl Default for CalibrationConfig {
     fn default() -> Self {
         Self {
@@ -2,7 +2,7 @@ mod sensor;
 
 use core::sync::atomic::{AtomicBool, Ordering};
 
-use embassy_futures::select::{select3, Either3};
+use embassy_futures::select::{select3, select4, Either3, Either4};
 use embassy_net::{dns::DnsQueryType, tcp::TcpSocket, Ipv4Address};
 use embassy_time::{Duration, Timer};
 use embedded_io_async::{Read, Write};
@@ -20,19 +20,22 @@ use serde::Serialize;
 use crate::{
     config::{CalibrationConfig, MqttConfig, CONFIG_CALIBRATION, CONFIG_MQTT},
     stpm::{
-        calibration::{ConversionParameters, IntCalibration},
+        calibration::{conversion_parameters, IntCalibration},
+        events::{StpmEvent, StpmInterruptEvent, EVENTS, INTERRUPTS},
+        harmonics::HARMONICS,
         SAMPLES,
     },
 };
 
-use self::sensor::{Device, Sensor, SensorDeviceClass};
+use self::sensor::{BinarySensor, Device, Sensor, SensorDeviceClass, StateClass};
 
 type Stack = embassy_net::Stack<WifiDevice<'static, WifiStaDevice>>;
 
 pub static MQTT_CONNECTED: AtomicBool = AtomicBool::new(false);
 
 const TCP_BUFFER_LEN: usize = 4096;
-const MQTT_BUFFER_LEN: usize = 1024;
+/// a sample with every value enabled needs more than 1 KiB
+const MQTT_BUFFER_LEN: usize = 2048;
 
 #[embassy_executor::task]
 pub async fn run_mqtt(stack: &'static Stack) {
@@ -43,13 +46,13 @@ pub async fn run_mqtt(stack: &'static Stack) {
     let mut scratch_buffer = [0; MQTT_BUFFER_LEN];
 
     let mut config = CONFIG_MQTT.wait().await;
-    let mut cal = to_mqtt_cal(&CONFIG_CALIBRATION.wait().await);
+    let mut calibration = CONFIG_CALIBRATION.wait().await;
 
     loop {
         let res = once_mqtt(
             stack,
             &mut config,
-            &mut cal,
+            &mut calibration,
             &mut tcp_buf_rx,
             &mut tcp_buf_tx,
             &mut mqtt_buf_rx,
@@ -70,7 +73,7 @@ pub async fn run_mqtt(stack: &'static Stack) {
 async fn once_mqtt(
     stack: &'static Stack,
     config: &mut MqttConfig,
-    cal: &mut [IntCalibration; 2],
+    calibration: &mut CalibrationConfig,
     tcp_buf_rx: &mut [u8],
     tcp_buf_tx: &mut [u8],
     mqtt_buf_rx: &mut [u8],
@@ -166,6 +169,23 @@ async fn once_mqtt(
     let _ = topic.push_str(&config.ha_unique_id);
     let _ = topic.push_str("/state");
 
+    // interrupts are only published while connected
+    let mut interrupts = INTERRUPTS.subscriber().ok()?;
+
+    // events (sag, swell, ...) are published on their own topic
+    let mut event_topic: String<128> = String::new();
+    let _ = event_topic.push_str(&config.ha_discovery_prefix);
+    let _ = event_topic.push_str("/sensor/");
+    let _ = event_topic.push_str(&config.ha_unique_id);
+    let _ = event_topic.push_str("/event");
+
+    // as are the results of the harmonic analysis
+    let mut harmonics_topic: String<128> = String::new();
+    let _ = harmonics_topic.push_str(&config.ha_discovery_prefix);
+    let _ = harmonics_topic.push_str("/sensor/");
+    let _ = harmonics_topic.push_str(&config.ha_unique_id);
+    let _ = harmonics_topic.push_str("/harmonics");
+
     // publish configurations at the very start
     let mut publish_config = true;
     // publish new samples as they arrive
@@ -179,63 +199,182 @@ async fn once_mqtt(
         let fut_mqtt = client.receive_message();
         let fut_samples = SAMPLES.wait();
         let fut_config = CONFIG_MQTT.wait();
+        let fut_events = select3(EVENTS.receive(), interrupts.next_message_pure(), HARMONICS.receive());
 
-        match select3(fut_mqtt, fut_samples, fut_config).await {
-            Either3::First(Ok((topic, msg))) => {
+        match select4(fut_mqtt, fut_samples, fut_config, fut_events).await {
+            Either4::First(Ok((topic, msg))) => {
                 println!("mqtt rx: {topic:?}");
                 // don't check the topic, we only subscribed to home assistant status
                 if msg == b"online" {
                     publish_config = true;
                 }
             }
-            Either3::First(Err(e)) => {
+            Either4::First(Err(e)) => {
                 println!("error receiving mqtt message: {e:?}");
                 return None;
             }
-            Either3::Second(samples) => {
+            Either4::Second(samples) => {
                 // update calibration?
                 if CONFIG_CALIBRATION.signaled() {
-                    *cal = to_mqtt_cal(&CONFIG_CALIBRATION.wait().await);
+                    *calibration = CONFIG_CALIBRATION.wait().await;
                 }
 
-                // apply cal
-                let samples: [_; 2] = core::array::from_fn(|i| cal[i].apply(samples[i]));
+                // apply cal, converted every time as it also depends on the
+                // line frequency
+                let cal = to_mqtt_cal(calibration);
+                let samples: [_; 2] = core::array::from_fn(|i| cal[i].apply(samples[i], super::zcr::is_plausible));
 
                 let mut ms: MqttSample = Default::default();
 
                 // set only the values that are configured
                 if config.channel_enable[0].frequency || config.channel_enable[1].frequency {
-                    ms.frequency = Some(super::zcr::get_frequency());
+                    // fall back to the period measured by the STPM if the
+                    // ZCR signal is missing
+                    if let Some(frequency) = super::zcr::get_frequency() {
+                        ms.frequency = Some(frequency);
+                        ms.frequency_source = Some("zcr");
+                    } else if let Some(frequency) = samples[0].frequency {
+                        ms.frequency = Some(frequency);
+                        ms.frequency_source = Some("stpm1");
+                    } else if let Some(frequency) = samples[1].frequency {
+                        ms.frequency = Some(frequency);
+                        ms.frequency_source = Some("stpm2");
+                    }
                 }
                 if config.channel_enable[0].voltage {
-                    ms.ch1_voltage_rms = Some(samples[0].voltage_rms);
+                    ms.ch1_voltage_rms = samples[0].voltage_rms;
                 }
                 if config.channel_enable[0].current {
-                    ms.ch1_current_rms = Some(samples[0].current_rms);
+                    ms.ch1_current_rms = samples[0].current_rms;
                 }
                 if config.channel_enable[0].active_power {
-                    ms.ch1_power_active = Some(samples[0].power_active);
+                    ms.ch1_power_active = samples[0].power_active;
                 }
                 if config.channel_enable[0].reactive_power {
-                    ms.ch1_power_reactive = Some(samples[0].power_reactive);
+                    ms.ch1_power_reactive = samples[0].power_reactive;
+                }
+                if config.channel_enable[0].apparent_power {
+                    ms.ch1_power_apparent = samples[0].power_apparent;
+                }
+                if config.channel_enable[0].power_factor {
+                    ms.ch1_power_factor = samples[0].power_factor;
+                }
+                if config.channel_enable[0].fundamental_power {
+                    ms.ch1_power_fundamental = samples[0].power_fundamental;
+                }
+                if config.channel_enable[0].harmonic_power {
+                    ms.ch1_power_harmonic = samples[0].power_harmonic;
                 }
                 if config.channel_enable[0].energy {
                     ms.ch1_energy_active = Some(samples[0].energy_active);
+                    ms.ch1_energy_gap = Some(samples[0].energy_gap as u8);
+                }
+                if config.channel_enable[0].import_export_energy {
+                    ms.ch1_energy_import = Some(samples[0].energy_import);
+                    ms.ch1_energy_export = Some(samples[0].energy_export);
+                }
+                if config.channel_enable[0].fundamental_energy {
+                    ms.ch1_energy_fundamental = Some(samples[0].energy_fundamental);
+                }
+                if config.channel_enable[0].reactive_energy {
+                    ms.ch1_energy_reactive = Some(samples[0].energy_reactive);
+                }
+                if config.channel_enable[0].apparent_energy {
+                    ms.ch1_energy_apparent = Some(samples[0].energy_apparent);
+                }
+                if config.channel_enable[0].overcurrent {
+                    ms.ch1_overcurrent = Some(samples[0].current_swell as u8);
+                }
+                if config.channel_enable[0].phase_angle {
+                    ms.ch1_phase_angle = samples[0].phase_angle;
+                }
+                if config.channel_enable[0].current_gain {
+                    ms.ch1_current_gain = Some(samples[0].current_gain);
+                }
+                if config.channel_enable[0].min_max {
+                    ms.ch1_voltage_rms_min = samples[0].voltage_rms_min;
+                    ms.ch1_voltage_rms_max = samples[0].voltage_rms_max;
+                    ms.ch1_current_rms_min = samples[0].current_rms_min;
+                    ms.ch1_current_rms_max = samples[0].current_rms_max;
+                    ms.ch1_power_active_min = samples[0].power_active_min;
+                    ms.ch1_power_active_max = samples[0].power_active_max;
                 }
                 if config.channel_enable[1].voltage {
-                    ms.ch2_voltage_rms = Some(samples[1].voltage_rms);
+                    ms.ch2_voltage_rms = samples[1].voltage_rms;
                 }
                 if config.channel_enable[1].current {
-                    ms.ch2_current_rms = Some(samples[1].current_rms);
+                    ms.ch2_current_rms = samples[1].current_rms;
                 }
                 if config.channel_enable[1].active_power {
-                    ms.ch2_power_active = Some(samples[1].power_active);
+                    ms.ch2_power_active = samples[1].power_active;
                 }
                 if config.channel_enable[1].reactive_power {
-                    ms.ch2_power_reactive = Some(samples[1].power_reactive);
+                    ms.ch2_power_reactive = samples[1].power_reactive;
+                }
+                if config.channel_enable[1].apparent_power {
+                    ms.ch2_power_apparent = samples[1].power_apparent;
+                }
+                if config.channel_enable[1].power_factor {
+                    ms.ch2_power_factor = samples[1].power_factor;
+                }
+                if config.channel_enable[1].fundamental_power {
+                    ms.ch2_power_fundamental = samples[1].power_fundamental;
+                }
+                if config.channel_enable[1].harmonic_power {
+                    ms.ch2_power_harmonic = samples[1].power_harmonic;
                 }
                 if config.channel_enable[1].energy {
                     ms.ch2_energy_active = Some(samples[1].energy_active);
+                    ms.ch2_energy_gap = Some(samples[1].energy_gap as u8);
+                }
+                if config.channel_enable[1].import_export_energy {
+                    ms.ch2_energy_import = Some(samples[1].energy_import);
+                    ms.ch2_energy_export = Some(samples[1].energy_export);
+                }
+                if config.channel_enable[1].fundamental_energy {
+                    ms.ch2_energy_fundamental = Some(samples[1].energy_fundamental);
+                }
+                if config.channel_enable[1].reactive_energy {
+                    ms.ch2_energy_reactive = Some(samples[1].energy_reactive);
+                }
+                if config.channel_enable[1].apparent_energy {
+                    ms.ch2_energy_apparent = Some(samples[1].energy_apparent);
+                }
+                if config.channel_enable[1].overcurrent {
+                    ms.ch2_overcurrent = Some(samples[1].current_swell as u8);
+                }
+                if config.channel_enable[1].phase_angle {
+                    ms.ch2_phase_angle = samples[1].phase_angle;
+                }
+                if config.channel_enable[1].current_gain {
+                    ms.ch2_current_gain = Some(samples[1].current_gain);
+                }
+                if config.channel_enable[1].min_max {
+                    ms.ch2_voltage_rms_min = samples[1].voltage_rms_min;
+                    ms.ch2_voltage_rms_max = samples[1].voltage_rms_max;
+                    ms.ch2_current_rms_min = samples[1].current_rms_min;
+                    ms.ch2_current_rms_max = samples[1].current_rms_max;
+                    ms.ch2_power_active_min = samples[1].power_active_min;
+                    ms.ch2_power_active_max = samples[1].power_active_max;
+                }
+                // combined channel
+                let total = &config.total_enable;
+                if total.active_power {
+                    ms.total_power_active = sum(samples[0].power_active, samples[1].power_active);
+                }
+                if total.reactive_power {
+                    ms.total_power_reactive = sum(samples[0].power_reactive, samples[1].power_reactive);
+                }
+                if total.apparent_power {
+                    ms.total_power_apparent = sum(samples[0].power_apparent, samples[1].power_apparent);
+                }
+                if total.energy {
+                    ms.total_energy_active = sum(Some(samples[0].energy_active), Some(samples[1].energy_active));
+                }
+                if total.import_export_energy {
+                    // by the direction of the summed power, not the sum of the channels
+                    ms.total_energy_import = Some(samples[0].energy_combined_import);
+                    ms.total_energy_export = Some(samples[0].energy_combined_export);
                 }
 
                 // send sample
@@ -249,12 +388,54 @@ async fn once_mqtt(
                     return None;
                 }
             }
-            Either3::Third(new_config) => {
+            Either4::Third(new_config) => {
                 // client holds references to config
                 core::mem::drop(client);
                 *config = new_config;
                 return Some(());
             }
+            Either4::Fourth(Either3::First(event)) => {
+                let ev = to_mqtt_event(&event, &to_mqtt_cal(calibration)[event.channel]);
+                let n = serde_json_core::to_slice(&ev, buffer).unwrap();
+
+                if let Err(e) = client
+                    .send_message(event_topic.as_str(), &buffer[..n], QoS0, false)
+                    .await
+                {
+                    println!("mqtt publish event failed {e:?}");
+                    return None;
+                }
+            }
+            Either4::Fourth(Either3::Second(interrupt)) => {
+                // sag / swell edges are published as a whole event instead
+                if interrupt.interrupt.is_event_edge() {
+                    continue;
+                }
+                let ev = to_mqtt_interrupt(&interrupt);
+                let n = serde_json_core::to_slice(&ev, buffer).unwrap();
+
+                if let Err(e) = client
+                    .send_message(event_topic.as_str(), &buffer[..n], QoS0, false)
+                    .await
+                {
+                    println!("mqtt publish event failed {e:?}");
+                    return None;
+                }
+            }
+            Either4::Fourth(Either3::Third(harmonics)) => {
+                if !config.channel_enable[harmonics.channel - 1].harmonics {
+                    continue;
+                }
+                let n = serde_json_core::to_slice(&harmonics, buffer).unwrap();
+
+                if let Err(e) = client
+                    .send_message(harmonics_topic.as_str(), &buffer[..n], QoS0, false)
+                    .await
+                {
+                    println!("mqtt publish harmonics failed {e:?}");
+                    return None;
+                }
+            }
         };
     }
 }
@@ -267,24 +448,67 @@ async fn publish_configurations<T: Read + Write, const MAX_PROPERTIES: usize, R:
 ) -> Option<()> {
     // entities to send
     let enable = &config.channel_enable;
-    use SensorDeviceClass::{Current, Energy, Frequency, Power, ReactivePower, Voltage};
+    let total = &config.total_enable;
+    use SensorDeviceClass::{
+        ApparentPower, Current, Energy, Frequency, Power, PowerFactor, ReactivePower, Voltage,
+    };
+    use StateClass::{Measurement, Total, TotalIncreasing};
 
     #[rustfmt::skip]
     let entities = [
         // ch 1
-        (0, "freq", "/1e4", "Hz", Frequency, "Frequency", enable[0].frequency),
-        (0, "volt1", "/1e3", "V", Voltage, "Voltage", enable[0].voltage),
-        (0, "curr1", "/1e4", "A", Current, "Current", enable[0].current),
-        (0, "powa1", "/1e3", "W", Power, "Power", enable[0].active_power),
-        (0, "powr1", "/1e3", "var", ReactivePower, "ReactivePower", enable[0].reactive_power),
-        (0, "engy1", "/1e3", "Wh", Energy, "Energy", enable[0].energy),
+        (0, "freq", "/1e4", "Hz", Frequency, Measurement, "Frequency", enable[0].frequency),
+        (0, "volt1", "/1e3", "V", Voltage, Measurement, "Voltage", enable[0].voltage),
+        (0, "curr1", "/1e4", "A", Current, Measurement, "Current", enable[0].current),
+        (0, "powa1", "/1e3", "W", Power, Measurement, "Power", enable[0].active_power),
+        (0, "powr1", "/1e3", "var", ReactivePower, Measurement, "ReactivePower", enable[0].reactive_power),
+        (0, "pows1", "/1e3", "VA", ApparentPower, Measurement, "ApparentPower", enable[0].apparent_power),
+        (0, "pf1", "/10", "%", PowerFactor, Measurement, "PowerFactor", enable[0].power_factor),
+        (0, "powf1", "/1e3", "W", Power, Measurement, "FundamentalPower", enable[0].fundamental_power),
+        (0, "powh1", "/1e3", "W", Power, Measurement, "HarmonicPower", enable[0].harmonic_power),
+        (0, "engy1", "/1e3", "Wh", Energy, Total, "Energy", enable[0].energy),
+        (0, "engi1", "/1e3", "Wh", Energy, TotalIncreasing, "EnergyImport", enable[0].import_export_energy),
+        (0, "enge1", "/1e3", "Wh", Energy, TotalIncreasing, "EnergyExport", enable[0].import_export_energy),
+        (0, "engf1", "/1e3", "Wh", Energy, Total, "FundamentalEnergy", enable[0].fundamental_energy),
+        (0, "engr1", "/1e3", "varh", SensorDeviceClass::None, Total, "ReactiveEnergy", enable[0].reactive_energy),
+        (0, "engs1", "/1e3", "VAh", SensorDeviceClass::None, TotalIncreasing, "ApparentEnergy", enable[0].apparent_energy),
+        (0, "pha1", "/1e3", "°", SensorDeviceClass::None, Measurement, "PhaseAngle", enable[0].phase_angle),
+        (0, "volt1_min", "/1e3", "V", Voltage, Measurement, "VoltageMin", enable[0].min_max),
+        (0, "volt1_max", "/1e3", "V", Voltage, Measurement, "VoltageMax", enable[0].min_max),
+        (0, "curr1_min", "/1e4", "A", Current, Measurement, "CurrentMin", enable[0].min_max),
+        (0, "curr1_max", "/1e4", "A", Current, Measurement, "CurrentMax", enable[0].min_max),
+        (0, "powa1_min", "/1e3", "W", Power, Measurement, "PowerMin", enable[0].min_max),
+        (0, "powa1_max", "/1e3", "W", Power, Measurement, "PowerMax", enable[0].min_max),
         // ch 2
-        (1, "freq", "/1e4", "Hz", Frequency, "Frequency", enable[1].frequency),
-        (1, "volt2", "/1e3", "V", Voltage, "Voltage", enable[1].voltage),
-        (1, "curr2", "/1e4", "A", Current, "Current", enable[1].current),
-        (1, "powa2", "/1e3", "W", Power, "Power", enable[1].active_power),
-        (1, "powr2", "/1e3", "var", ReactivePower, "ReactivePower", enable[1].reactive_power),
-        (1, "engy2", "/1e3", "Wh", Energy, "Energy", enable[1].energy),
+        (1, "freq", "/1e4", "Hz", Frequency, Measurement, "Frequency", enable[1].frequency),
+        (1, "volt2", "/1e3", "V", Voltage, Measurement, "Voltage", enable[1].voltage),
+        (1, "curr2", "/1e4", "A", Current, Measurement, "Current", enable[1].current),
+        (1, "powa2", "/1e3", "W", Power, Measurement, "Power", enable[1].active_power),
+        (1, "powr2", "/1e3", "var", ReactivePower, Measurement, "ReactivePower", enable[1].reactive_power),
+        (1, "pows2", "/1e3", "VA", ApparentPower, Measurement, "ApparentPower", enable[1].apparent_power),
+        (1, "pf2", "/10", "%", PowerFactor, Measurement, "PowerFactor", enable[1].power_factor),
+        (1, "powf2", "/1e3", "W", Power, Measurement, "FundamentalPower", enable[1].fundamental_power),
+        (1, "powh2", "/1e3", "W", Power, Measurement, "HarmonicPower", enable[1].harmonic_power),
+        (1, "engy2", "/1e3", "Wh", Energy, Total, "Energy", enable[1].energy),
+        (1, "engi2", "/1e3", "Wh", Energy, TotalIncreasing, "EnergyImport", enable[1].import_export_energy),
+        (1, "enge2", "/1e3", "Wh", Energy, TotalIncreasing, "EnergyExport", enable[1].import_export_energy),
+        (1, "engf2", "/1e3", "Wh", Energy, Total, "FundamentalEnergy", enable[1].fundamental_energy),
+        (1, "engr2", "/1e3", "varh", SensorDeviceClass::None, Total, "ReactiveEnergy", enable[1].reactive_energy),
+        (1, "engs2", "/1e3", "VAh", SensorDeviceClass::None, TotalIncreasing, "ApparentEnergy", enable[1].apparent_energy),
+        (1, "pha2", "/1e3", "°", SensorDeviceClass::None, Measurement, "PhaseAngle", enable[1].phase_angle),
+        (1, "volt2_min", "/1e3", "V", Voltage, Measurement, "VoltageMin", enable[1].min_max),
+        (1, "volt2_max", "/1e3", "V", Voltage, Measurement, "VoltageMax", enable[1].min_max),
+        (1, "curr2_min", "/1e4", "A", Current, Measurement, "CurrentMin", enable[1].min_max),
+        (1, "curr2_max", "/1e4", "A", Current, Measurement, "CurrentMax", enable[1].min_max),
+        (1, "powa2_min", "/1e3", "W", Power, Measurement, "PowerMin", enable[1].min_max),
+        (1, "powa2_max", "/1e3", "W", Power, Measurement, "PowerMax", enable[1].min_max),
+        // combined channel
+        (2, "powat", "/1e3", "W", Power, Measurement, "Power", total.active_power),
+        (2, "powrt", "/1e3", "var", ReactivePower, Measurement, "ReactivePower", total.reactive_power),
+        (2, "powst", "/1e3", "VA", ApparentPower, Measurement, "ApparentPower", total.apparent_power),
+        (2, "engyt", "/1e3", "Wh", Energy, Total, "Energy", total.energy),
+        (2, "engit", "/1e3", "Wh", Energy, TotalIncreasing, "EnergyImport", total.import_export_energy),
+        (2, "enget", "/1e3", "Wh", Energy, TotalIncreasing, "EnergyExport", total.import_export_energy),
     ];
 
     // entity template
@@ -297,27 +521,30 @@ async fn publish_configurations<T: Read + Write, const MAX_PROPERTIES: usize, R:
         expire_after: 10,
         icon: None,
         device_class: Power,
+        state_class: None,
         unit_of_measurement: "",
         suggested_display_precision: None,
         json_name: "",
         json_conv: "",
         name: String::new(),
+        entity_category: None,
     };
 
     // go through all entities
-    for (i, json_name, json_conv, unit, class, name, enabled) in entities {
+    for (i, json_name, json_conv, unit, class, state_class, name, enabled) in entities {
         if !enabled {
             continue;
         }
 
         // copy details
         sensor.device_class = class;
+        sensor.state_class = Some(state_class);
         sensor.unit_of_measurement = unit;
         sensor.json_name = json_name;
         sensor.json_conv = json_conv;
 
         sensor.name.clear();
-        let _ = sensor.name.push_str(&config.channel_names[i]);
+        let _ = sensor.name.push_str(channel_name(config, i));
         let _ = sensor.name.push(' ');
         let _ = sensor.name.push_str(name);
 
@@ -340,6 +567,95 @@ async fn publish_configurations<T: Read + Write, const MAX_PROPERTIES: usize, R:
             .ok()?;
     }
 
+    // diagnostic sensors, without unit and device class
+    let diagnostic_entities = [
+        (0, "gain1", "CurrentGain", enable[0].current_gain),
+        (1, "gain2", "CurrentGain", enable[1].current_gain),
+    ];
+
+    sensor.device_class = SensorDeviceClass::None;
+    sensor.state_class = Some(Measurement);
+    sensor.unit_of_measurement = "";
+    sensor.json_conv = "";
+    sensor.entity_category = Some("diagnostic");
+
+    for (i, json_name, name, enabled) in diagnostic_entities {
+        if !enabled {
+            continue;
+        }
+
+        sensor.json_name = json_name;
+
+        sensor.name.clear();
+        let _ = sensor.name.push_str(&config.channel_names[i]);
+        let _ = sensor.name.push(' ');
+        let _ = sensor.name.push_str(name);
+
+        let n = serde_json_core::to_slice(&sensor, buffer).unwrap();
+
+        let mut config_topic: String<128> = String::new();
+        let _ = config_topic.push_str(&config.ha_discovery_prefix);
+        let _ = config_topic.push_str("/sensor/");
+        let _ = config_topic.push_str(&config.ha_unique_id);
+        let _ = config_topic.push_str("/");
+        let _ = config_topic.push_str(json_name);
+        let _ = config_topic.push_str("/config");
+
+        client
+            .send_message(&config_topic, &buffer[..n], QoS0, false)
+            .await
+            .ok()?;
+    }
+
+    // binary sensors
+    let binary_entities = [
+        (0, "ocur1", "problem", "Overcurrent", enable[0].overcurrent),
+        (1, "ocur2", "problem", "Overcurrent", enable[1].overcurrent),
+        (0, "egap1", "problem", "EnergyGap", enable[0].energy),
+        (1, "egap2", "problem", "EnergyGap", enable[1].energy),
+    ];
+
+    let mut binary_sensor = BinarySensor {
+        state_topic,
+        device: Device {
+            identifiers: &config.ha_unique_id,
+            name: &config.ha_device_name,
+        },
+        expire_after: 10,
+        device_class: "",
+        json_name: "",
+        name: String::new(),
+    };
+
+    for (i, json_name, class, name, enabled) in binary_entities {
+        if !enabled {
+            continue;
+        }
+
+        binary_sensor.device_class = class;
+        binary_sensor.json_name = json_name;
+
+        binary_sensor.name.clear();
+        let _ = binary_sensor.name.push_str(&config.channel_names[i]);
+        let _ = binary_sensor.name.push(' ');
+        let _ = binary_sensor.name.push_str(name);
+
+        let n = serde_json_core::to_slice(&binary_sensor, buffer).unwrap();
+
+        let mut config_topic: String<128> = String::new();
+        let _ = config_topic.push_str(&config.ha_discovery_prefix);
+        let _ = config_topic.push_str("/binary_sensor/");
+        let _ = config_topic.push_str(&config.ha_unique_id);
+        let _ = config_topic.push_str("/");
+        let _ = config_topic.push_str(json_name);
+        let _ = config_topic.push_str("/config");
+
+        client
+            .send_message(&config_topic, &buffer[..n], QoS0, false)
+            .await
+            .ok()?;
+    }
+
     Some(())
 }
 
@@ -347,6 +663,8 @@ async fn publish_configurations<T: Read + Write, const MAX_PROPERTIES: usize, R:
 struct MqttSample {
     #[serde(rename = "freq", skip_serializing_if = "Option::is_none")]
     pub frequency: Option<u64>,
+    #[serde(rename = "fsrc", skip_serializing_if = "Option::is_none")]
+    pub frequency_source: Option<&'static str>,
 
     #[serde(rename = "volt1", skip_serializing_if = "Option::is_none")]
     pub ch1_voltage_rms: Option<u64>,
@@ -356,8 +674,46 @@ struct MqttSample {
     pub ch1_power_active: Option<i64>,
     #[serde(rename = "powr1", skip_serializing_if = "Option::is_none")]
     pub ch1_power_reactive: Option<i64>,
+    #[serde(rename = "pows1", skip_serializing_if = "Option::is_none")]
+    pub ch1_power_apparent: Option<i64>,
+    #[serde(rename = "pf1", skip_serializing_if = "Option::is_none")]
+    pub ch1_power_factor: Option<i64>,
+    #[serde(rename = "powf1", skip_serializing_if = "Option::is_none")]
+    pub ch1_power_fundamental: Option<i64>,
+    #[serde(rename = "powh1", skip_serializing_if = "Option::is_none")]
+    pub ch1_power_harmonic: Option<i64>,
     #[serde(rename = "engy1", skip_serializing_if = "Option::is_none")]
     pub ch1_energy_active: Option<i64>,
+    #[serde(rename = "engi1", skip_serializing_if = "Option::is_none")]
+    pub ch1_energy_import: Option<i64>,
+    #[serde(rename = "enge1", skip_serializing_if = "Option::is_none")]
+    pub ch1_energy_export: Option<i64>,
+    #[serde(rename = "engf1", skip_serializing_if = "Option::is_none")]
+    pub ch1_energy_fundamental: Option<i64>,
+    #[serde(rename = "engr1", skip_serializing_if = "Option::is_none")]
+    pub ch1_energy_reactive: Option<i64>,
+    #[serde(rename = "engs1", skip_serializing_if = "Option::is_none")]
+    pub ch1_energy_apparent: Option<i64>,
+    #[serde(rename = "egap1", skip_serializing_if = "Option::is_none")]
+    pub ch1_energy_gap: Option<u8>,
+    #[serde(rename = "ocur1", skip_serializing_if = "Option::is_none")]
+    pub ch1_overcurrent: Option<u8>,
+    #[serde(rename = "pha1", skip_serializing_if = "Option::is_none")]
+    pub ch1_phase_angle: Option<i64>,
+    #[serde(rename = "gain1", skip_serializing_if = "Option::is_none")]
+    pub ch1_current_gain: Option<u8>,
+    #[serde(rename = "volt1_min", skip_serializing_if = "Option::is_none")]
+    pub ch1_voltage_rms_min: Option<u64>,
+    #[serde(rename = "volt1_max", skip_serializing_if = "Option::is_none")]
+    pub ch1_voltage_rms_max: Option<u64>,
+    #[serde(rename = "curr1_min", skip_serializing_if = "Option::is_none")]
+    pub ch1_current_rms_min: Option<u64>,
+    #[serde(rename = "curr1_max", skip_serializing_if = "Option::is_none")]
+    pub ch1_current_rms_max: Option<u64>,
+    #[serde(rename = "powa1_min", skip_serializing_if = "Option::is_none")]
+    pub ch1_power_active_min: Option<i64>,
+    #[serde(rename = "powa1_max", skip_serializing_if = "Option::is_none")]
+    pub ch1_power_active_max: Option<i64>,
 
     #[serde(rename = "volt2", skip_serializing_if = "Option::is_none")]
     pub ch2_voltage_rms: Option<u64>,
@@ -367,17 +723,134 @@ struct MqttSample {
     pub ch2_power_active: Option<i64>,
     #[serde(rename = "powr2", skip_serializing_if = "Option::is_none")]
     pub ch2_power_reactive: Option<i64>,
+    #[serde(rename = "pows2", skip_serializing_if = "Option::is_none")]
+    pub ch2_power_apparent: Option<i64>,
+    #[serde(rename = "pf2", skip_serializing_if = "Option::is_none")]
+    pub ch2_power_factor: Option<i64>,
+    #[serde(rename = "powf2", skip_serializing_if = "Option::is_none")]
+    pub ch2_power_fundamental: Option<i64>,
+    #[serde(rename = "powh2", skip_serializing_if = "Option::is_none")]
+    pub ch2_power_harmonic: Option<i64>,
     #[serde(rename = "engy2", skip_serializing_if = "Option::is_none")]
     pub ch2_energy_active: Option<i64>,
+    #[serde(rename = "engi2", skip_serializing_if = "Option::is_none")]
+    pub ch2_energy_import: Option<i64>,
+    #[serde(rename = "enge2", skip_serializing_if = "Option::is_none")]
+    pub ch2_energy_export: Option<i64>,
+    #[serde(rename = "engf2", skip_serializing_if = "Option::is_none")]
+    pub ch2_energy_fundamental: Option<i64>,
+    #[serde(rename = "engr2", skip_serializing_if = "Option::is_none")]
+    pub ch2_energy_reactive: Option<i64>,
+    #[serde(rename = "engs2", skip_serializing_if = "Option::is_none")]
+    pub ch2_energy_apparent: Option<i64>,
+    #[serde(rename = "egap2", skip_serializing_if = "Option::is_none")]
+    pub ch2_energy_gap: Option<u8>,
+    #[serde(rename = "ocur2", skip_serializing_if = "Option::is_none")]
+    pub ch2_overcurrent: Option<u8>,
+    #[serde(rename = "pha2", skip_serializing_if = "Option::is_none")]
+    pub ch2_phase_angle: Option<i64>,
+    #[serde(rename = "gain2", skip_serializing_if = "Option::is_none")]
+    pub ch2_current_gain: Option<u8>,
+    #[serde(rename = "volt2_min", skip_serializing_if = "Option::is_none")]
+    pub ch2_voltage_rms_min: Option<u64>,
+    #[serde(rename = "volt2_max", skip_serializing_if = "Option::is_none")]
+    pub ch2_voltage_rms_max: Option<u64>,
+    #[serde(rename = "curr2_min", skip_serializing_if = "Option::is_none")]
+    pub ch2_current_rms_min: Option<u64>,
+    #[serde(rename = "curr2_max", skip_serializing_if = "Option::is_none")]
+    pub ch2_current_rms_max: Option<u64>,
+    #[serde(rename = "powa2_min", skip_serializing_if = "Option::is_none")]
+    pub ch2_power_active_min: Option<i64>,
+    #[serde(rename = "powa2_max", skip_serializing_if = "Option::is_none")]
+    pub ch2_power_active_max: Option<i64>,
+
+    #[serde(rename = "powat", skip_serializing_if = "Option::is_none")]
+    pub total_power_active: Option<i64>,
+    #[serde(rename = "powrt", skip_serializing_if = "Option::is_none")]
+    pub total_power_reactive: Option<i64>,
+    #[serde(rename = "powst", skip_serializing_if = "Option::is_none")]
+    pub total_power_apparent: Option<i64>,
+    #[serde(rename = "engyt", skip_serializing_if = "Option::is_none")]
+    pub total_energy_active: Option<i64>,
+    #[serde(rename = "engit", skip_serializing_if = "Option::is_none")]
+    pub total_energy_import: Option<i64>,
+    #[serde(rename = "enget", skip_serializing_if = "Option::is_none")]
+    pub total_energy_export: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct MqttEvent {
+    #[serde(rename = "ch")]
+    pub channel: usize,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    /// uptime in ms at the start of the event
+    pub start: u64,
+    /// in ms
+    #[serde(rename = "dur")]
+    pub duration: u64,
+    /// lowest (sag) or highest (swell) voltage, 3 decimal places
+    #[serde(rename = "volt", skip_serializing_if = "Option::is_none")]
+    pub voltage_rms: Option<u64>,
+    /// highest current, 4 decimal places
+    #[serde(rename = "curr", skip_serializing_if = "Option::is_none")]
+    pub current_rms: Option<u64>,
+    /// peak active power, 3 decimal places
+    #[serde(rename = "pow", skip_serializing_if = "Option::is_none")]
+    pub power: Option<i64>,
+}
+
+fn to_mqtt_event(event: &StpmEvent, cal: &IntCalibration) -> MqttEvent {
+    use crate::stpm::events::StpmEventKind::*;
+
+    let mut ev = MqttEvent {
+        channel: event.channel + 1,
+        kind: event.kind.name(),
+        start: event.start.as_millis(),
+        duration: event.duration.as_millis(),
+        voltage_rms: None,
+        current_rms: None,
+        power: None,
+    };
+    match event.kind {
+        VoltageSag | VoltageSwell => ev.voltage_rms = cal.voltage(event.value),
+        CurrentSwell => ev.current_rms = cal.current(event.value),
+        Inrush => ev.power = i64::try_from(event.value).ok().and_then(|power| cal.power(power)),
+    }
+    ev
+}
+
+#[derive(Serialize)]
+struct MqttInterrupt {
+    #[serde(rename = "ch", skip_serializing_if = "Option::is_none")]
+    pub channel: Option<usize>,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    /// uptime in ms when the interrupt was handled
+    pub start: u64,
+}
+
+fn to_mqtt_interrupt(interrupt: &StpmInterruptEvent) -> MqttInterrupt {
+    MqttInterrupt {
+        channel: interrupt.channel.map(|ch| ch + 1),
+        kind: interrupt.interrupt.name(),
+        start: interrupt.time.as_millis(),
+    }
+}
+
+/// index 2 is the combined channel
+fn channel_name(config: &MqttConfig, channel: usize) -> &str {
+    match config.channel_names.get(channel) {
+        Some(name) => name,
+        None => &config.total_name,
+    }
+}
+
+/// sum of both channels, None if either is missing or the sum overflows
+fn sum(a: Option<i64>, b: Option<i64>) -> Option<i64> {
+    a?.checked_add(b?)
 }
 
 fn to_mqtt_cal(cal: &CalibrationConfig) -> [IntCalibration; 2] {
-    core::array::from_fn(|i| {
-        let param = ConversionParameters {
-            voltage_divider_factor: cal.channels[i].voltage_divider_factor,
-            current_shunt: cal.channels[i].current_shunt,
-            oscillator_factor: cal.frequency_stpm_adjust,
-        };
-        param.to_float_cal().to_int_cal()
-    })
+    core::array::from_fn(|i| conversion_parameters(cal, i).to_float_cal().to_int_cal())
 }
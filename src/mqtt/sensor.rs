@@ -21,11 +21,14 @@ pub struct Sensor<'a> {
     pub expire_after: u32,
     pub icon: Option<&'a str>,
     pub device_class: SensorDeviceClass,
+    pub state_class: Option<StateClass>,
     pub unit_of_measurement: &'a str,
     pub suggested_display_precision: Option<u8>,
     pub json_name: &'a str,
     pub json_conv: &'a str,
     pub name: String<64>,
+    /// e.g. "diagnostic"
+    pub entity_category: Option<&'a str>,
 }
 
 impl<'a> Serialize for Sensor<'a> {
@@ -43,13 +46,21 @@ impl<'a> Serialize for Sensor<'a> {
         if self.device_class != SensorDeviceClass::None {
             st.serialize_entry("dev_cla", &self.device_class)?;
         }
+        if let Some(state_class) = self.state_class.as_ref() {
+            st.serialize_entry("stat_cla", state_class)?;
+        }
         if self.expire_after != 0 {
             st.serialize_entry("exp_aft", &self.expire_after)?;
         }
         if let Some(prec) = self.suggested_display_precision {
             st.serialize_entry("sug_dsp_prc", &prec)?;
         }
-        st.serialize_entry("unit_of_meas", self.unit_of_measurement)?;
+        if let Some(category) = self.entity_category {
+            st.serialize_entry("ent_cat", category)?;
+        }
+        if !self.unit_of_measurement.is_empty() {
+            st.serialize_entry("unit_of_meas", self.unit_of_measurement)?;
+        }
         st.serialize_entry("name", self.name.as_str())?;
 
         // value template
@@ -71,6 +82,63 @@ impl<'a> Serialize for Sensor<'a> {
     }
 }
 
+/// binary sensor, reads 0 / 1 from the state json
+pub struct BinarySensor<'a> {
+    pub state_topic: &'a str,
+    pub device: Device<'a>,
+    pub expire_after: u32,
+    pub device_class: &'a str,
+    pub json_name: &'a str,
+    pub name: String<64>,
+}
+
+impl<'a> Serialize for BinarySensor<'a> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let mut buffer: String<64> = String::new();
+
+        let mut st = s.serialize_map(None)?;
+
+        st.serialize_entry("stat_t", self.state_topic)?;
+        st.serialize_entry("dev", &self.device)?;
+        if !self.device_class.is_empty() {
+            st.serialize_entry("dev_cla", self.device_class)?;
+        }
+        if self.expire_after != 0 {
+            st.serialize_entry("exp_aft", &self.expire_after)?;
+        }
+        st.serialize_entry("name", self.name.as_str())?;
+        st.serialize_entry("pl_on", "1")?;
+        st.serialize_entry("pl_off", "0")?;
+
+        // value template
+        buffer.clear();
+        buffer.push_str("{{ value_json.").unwrap();
+        buffer.push_str(self.json_name).unwrap();
+        buffer.push_str(" }}").unwrap();
+        st.serialize_entry("val_tpl", buffer.as_str())?;
+
+        // unique id
+        buffer.clear();
+        buffer.push_str(self.device.identifiers).unwrap();
+        buffer.push('.').unwrap();
+        buffer.push_str(self.json_name).unwrap();
+        st.serialize_entry("uniq_id", buffer.as_str())?;
+
+        st.end()
+    }
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StateClass {
+    /// : Current value, e.g. power or voltage
+    Measurement,
+    /// : Total amount that can increase and decrease, e.g. net energy
+    Total,
+    /// : Monotonically increasing total, a decrease is treated as a reset
+    TotalIncreasing,
+}
+
 #[allow(dead_code)]
 #[derive(Serialize, Default, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
@@ -0,0 +1,204 @@
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, pubsub::PubSubChannel};
+use embassy_time::{Duration, Instant};
+
+use super::chip::*;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StpmEventKind {
+    VoltageSag,
+    VoltageSwell,
+    CurrentSwell,
+    /// momentary active power above the inrush threshold
+    Inrush,
+}
+
+impl StpmEventKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            StpmEventKind::VoltageSag => "voltage_sag",
+            StpmEventKind::VoltageSwell => "voltage_swell",
+            StpmEventKind::CurrentSwell => "current_swell",
+            StpmEventKind::Inrush => "inrush",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct StpmEvent {
+    pub channel: usize,
+    pub kind: StpmEventKind,
+    pub start: Instant,
+    pub duration: Duration,
+    /// lowest (sag) or highest (swell) raw RMS value seen during the event,
+    /// or the peak raw active power (inrush). currents and powers are scaled
+    /// to gain 16 like in RawSampleApp
+    pub value: u64,
+}
+
+/// finished events, sent to MQTT
+pub static EVENTS: Channel<CriticalSectionRawMutex, StpmEvent, 8> = Channel::new();
+
+/// a single bit of the chip status registers
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StpmInterrupt {
+    VoltageSagStart,
+    VoltageSagEnd,
+    VoltageSwellStart,
+    VoltageSwellEnd,
+    CurrentSwellStart,
+    CurrentSwellEnd,
+    ActivePowerSign,
+    ReactivePowerSign,
+    ActiveEnergyOverflow,
+    ReactiveEnergyOverflow,
+    CrcError,
+}
+
+impl StpmInterrupt {
+    /// all interrupts with a per-channel status bit in DSP_SR1 / DSP_SR2
+    pub const DSP: [(StpmInterrupt, u32); 10] = [
+        (StpmInterrupt::VoltageSagStart, SR_VOLTAGE_SAG_START),
+        (StpmInterrupt::VoltageSagEnd, SR_VOLTAGE_SAG_END),
+        (StpmInterrupt::VoltageSwellStart, SR_VOLTAGE_SWELL_START),
+        (StpmInterrupt::VoltageSwellEnd, SR_VOLTAGE_SWELL_END),
+        (StpmInterrupt::CurrentSwellStart, SR_CURRENT_SWELL_START),
+        (StpmInterrupt::CurrentSwellEnd, SR_CURRENT_SWELL_END),
+        (StpmInterrupt::ActivePowerSign, SR_ACTIVE_POWER_SIGN),
+        (StpmInterrupt::ReactivePowerSign, SR_REACTIVE_POWER_SIGN),
+        (StpmInterrupt::ActiveEnergyOverflow, SR_ACTIVE_ENERGY_OVERFLOW),
+        (StpmInterrupt::ReactiveEnergyOverflow, SR_REACTIVE_ENERGY_OVERFLOW),
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            StpmInterrupt::VoltageSagStart => "voltage_sag_start",
+            StpmInterrupt::VoltageSagEnd => "voltage_sag_end",
+            StpmInterrupt::VoltageSwellStart => "voltage_swell_start",
+            StpmInterrupt::VoltageSwellEnd => "voltage_swell_end",
+            StpmInterrupt::CurrentSwellStart => "current_swell_start",
+            StpmInterrupt::CurrentSwellEnd => "current_swell_end",
+            StpmInterrupt::ActivePowerSign => "active_power_sign",
+            StpmInterrupt::ReactivePowerSign => "reactive_power_sign",
+            StpmInterrupt::ActiveEnergyOverflow => "active_energy_overflow",
+            StpmInterrupt::ReactiveEnergyOverflow => "reactive_energy_overflow",
+            StpmInterrupt::CrcError => "crc_error",
+        }
+    }
+
+    /// sag and swell interrupts are reported as StpmEvent once they ended
+    pub fn is_event_edge(&self) -> bool {
+        !matches!(
+            self,
+            StpmInterrupt::ActivePowerSign
+                | StpmInterrupt::ReactivePowerSign
+                | StpmInterrupt::ActiveEnergyOverflow
+                | StpmInterrupt::ReactiveEnergyOverflow
+                | StpmInterrupt::CrcError
+        )
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct StpmInterruptEvent {
+    /// None for interrupts that are not tied to a channel (CRC error)
+    pub channel: Option<usize>,
+    pub interrupt: StpmInterrupt,
+    /// when the status registers were read
+    pub time: Instant,
+}
+
+/// every interrupt read from the chip, older ones are dropped if a subscriber
+/// falls behind
+pub static INTERRUPTS: PubSubChannel<CriticalSectionRawMutex, StpmInterruptEvent, 16, 2, 1> = PubSubChannel::new();
+
+/// splits the status registers into single interrupts and publishes them
+pub fn publish_interrupts(status: &StpmStatus, time: Instant) {
+    let publisher = INTERRUPTS.immediate_publisher();
+
+    for (channel, sr) in status.dsp.iter().enumerate() {
+        for (interrupt, bit) in StpmInterrupt::DSP {
+            if sr & bit != 0 {
+                publisher.publish_immediate(StpmInterruptEvent {
+                    channel: Some(channel),
+                    interrupt,
+                    time,
+                });
+            }
+        }
+    }
+
+    if status.us & US_CRC_ERROR != 0 {
+        publisher.publish_immediate(StpmInterruptEvent {
+            channel: None,
+            interrupt: StpmInterrupt::CrcError,
+            time,
+        });
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct FinishedEvent {
+    pub start: Instant,
+    pub duration: Duration,
+    pub min: u64,
+    pub max: u64,
+}
+
+/// follows a single event condition (e.g. sag on channel 1) from tick to tick
+#[derive(Default)]
+pub struct EventTracker {
+    active: Option<FinishedEvent>,
+}
+
+impl EventTracker {
+    pub fn is_active(&self) -> bool {
+        self.active.is_some()
+    }
+
+    /// updates min / max of an active event without changing its state, for
+    /// events whose start and end are reported by interrupt
+    pub fn observe(&mut self, value: u64) {
+        if let Some(event) = &mut self.active {
+            event.min = event.min.min(value);
+            event.max = event.max.max(value);
+        }
+    }
+
+    /// `chip_time` is the event duration from the time registers, it is used
+    /// to back-date the start of the event and for the final duration, since
+    /// the chip counts in much smaller steps than our sample ticks.
+    /// returns the event once the condition is gone.
+    pub fn update(&mut self, active: bool, value: u64, chip_time: u32) -> Option<FinishedEvent> {
+        let now = Instant::now();
+        let chip_duration = Duration::from_micros(chip_time as u64 * EVENT_TIME_LSB_US);
+
+        match (&mut self.active, active) {
+            (None, false) => None,
+            (None, true) => {
+                self.active = Some(FinishedEvent {
+                    start: now.checked_sub(chip_duration).unwrap_or(now),
+                    duration: chip_duration,
+                    min: value,
+                    max: value,
+                });
+                None
+            }
+            (Some(event), true) => {
+                event.duration = event.duration.max(chip_duration);
+                event.min = event.min.min(value);
+                event.max = event.max.max(value);
+                None
+            }
+            (Some(event), false) => {
+                // the chip keeps the duration of the last event after it ended
+                event.duration = event.duration.max(chip_duration);
+                // events reported by interrupt may end before the chip counted
+                // a single step
+                if event.duration == Duration::from_ticks(0) {
+                    event.duration = now - event.start;
+                }
+                self.active.take()
+            }
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use super::chip::StpmCurrentGain;
+
+/// raw current RMS values have 17 bits
+const CURRENT_RMS_FULL_SCALE: u32 = 1 << 17;
+/// switch to a lower gain above half of the full scale, the peaks of a
+/// distorted current reach the ADC limit long before the RMS value does
+const GAIN_DOWN_THRESHOLD: u32 = CURRENT_RMS_FULL_SCALE / 2;
+/// switch to a higher gain below a fifth of the full scale, after doubling the
+/// gain the value is still well below GAIN_DOWN_THRESHOLD
+const GAIN_UP_THRESHOLD: u32 = CURRENT_RMS_FULL_SCALE / 5;
+/// samples in a row below GAIN_UP_THRESHOLD before switching up
+const GAIN_UP_SAMPLES: u32 = 20;
+/// samples after a switch during which the RMS filter settles, no further
+/// switching in this time
+const GAIN_SETTLE_SAMPLES: u32 = 4;
+
+/// picks the current gain of a channel from its RMS value
+pub struct GainRanger {
+    pub gain: StpmCurrentGain,
+    auto: bool,
+    below_count: u32,
+    settle_count: u32,
+}
+
+impl GainRanger {
+    pub fn new(gain: StpmCurrentGain, auto: bool) -> Self {
+        Self {
+            gain,
+            auto,
+            below_count: 0,
+            settle_count: 0,
+        }
+    }
+
+    /// `current_rms` is the raw value at the current gain.
+    /// returns the new gain if the gain should be switched.
+    pub fn update(&mut self, current_rms: u32) -> Option<StpmCurrentGain> {
+        if !self.auto {
+            return None;
+        }
+
+        if self.settle_count > 0 {
+            self.settle_count -= 1;
+            return None;
+        }
+
+        // switch down immediately, the signal might be clipping
+        let new_gain = if current_rms > GAIN_DOWN_THRESHOLD {
+            self.gain.lower()
+        } else if current_rms < GAIN_UP_THRESHOLD {
+            self.below_count = self.below_count.saturating_add(1);
+            if self.below_count >= GAIN_UP_SAMPLES {
+                self.gain.higher()
+            } else {
+                None
+            }
+        } else {
+            self.below_count = 0;
+            None
+        }?;
+
+        self.gain = new_gain;
+        self.below_count = 0;
+        self.settle_count = GAIN_SETTLE_SAMPLES;
+        Some(new_gain)
+    }
+}
@@ -0,0 +1,239 @@
+use core::cell::Cell;
+
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    channel::Channel,
+    mutex::Mutex as AsyncMutex,
+    signal::Signal,
+};
+use embassy_time::{Duration, Instant};
+use serde::Serialize;
+
+use energy_core::math::{fft, isqrt, FFT_LEN};
+
+use super::{
+    chip::{Reader, Reg, Stpm},
+    driver::StpmDriver,
+    sample::PERIOD_CLOCK_HZ,
+};
+
+/// number of line cycles in one capture
+pub const CAPTURE_CYCLES: usize = 4;
+/// harmonics 1 (fundamental) to 15. the resampled capture can represent
+/// harmonics up to FFT_LEN / CAPTURE_CYCLES / 2 = 64, only harmonics above
+/// 113 would alias into the reported ones. that is far above the bandwidth of
+/// the chip (3.6 kHz).
+pub const NUM_HARMONICS: usize = 15;
+/// raw samples needed to sample the highest reported harmonic, a slower link
+/// would alias the harmonics already before resampling
+const MIN_RAW_SAMPLES: usize = 2 * (NUM_HARMONICS + 1) * CAPTURE_CYCLES;
+/// shortest interval of periodic captures in seconds, every capture pauses
+/// the sample loop
+pub const MIN_INTERVAL_SECS: u32 = 10;
+/// raw samples per capture, enough for 4 cycles at 45 Hz and 10 kHz
+const MAX_RAW_SAMPLES: usize = 1024;
+
+/// channel to capture (0 or 1), from the HTTP API
+pub static CAPTURE_REQUEST: Signal<CriticalSectionRawMutex, usize> = Signal::new();
+/// last finished capture of each channel, for the HTTP API
+pub static LATEST_HARMONICS: Mutex<CriticalSectionRawMutex, Cell<[Option<HarmonicsResult>; 2]>> =
+    Mutex::new(Cell::new([None; 2]));
+/// finished captures, for MQTT
+pub static HARMONICS: Channel<CriticalSectionRawMutex, HarmonicsResult, 2> = Channel::new();
+/// raw samples of the running capture, static so the 12 KB are not part of
+/// the stpm task
+static CAPTURE_BUFFER: AsyncMutex<CriticalSectionRawMutex, [RawPoint; MAX_RAW_SAMPLES]> =
+    AsyncMutex::new([RawPoint::ZERO; MAX_RAW_SAMPLES]);
+
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct Harmonics {
+    /// amplitude of each harmonic relative to the fundamental, 2 decimal
+    /// places in percent (the fundamental is always 10000)
+    #[serde(rename = "mag")]
+    pub magnitudes: [u32; NUM_HARMONICS],
+    /// total harmonic distortion, 2 decimal places in percent
+    pub thd: u32,
+}
+
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct HarmonicsResult {
+    /// 1 or 2, like in events
+    #[serde(rename = "ch")]
+    pub channel: usize,
+    /// uptime in ms at the start of the capture
+    pub time: u64,
+    /// number of raw samples taken during the capture
+    #[serde(rename = "n")]
+    pub num_samples: usize,
+    #[serde(rename = "volt")]
+    pub voltage: Harmonics,
+    #[serde(rename = "curr")]
+    pub current: Harmonics,
+}
+
+#[derive(Debug)]
+pub enum CaptureError<E> {
+    Driver(E),
+    /// no line period, the voltage is missing
+    NoPeriod,
+    /// the raw sample buffer filled up before the end of the capture
+    BufferFull,
+    /// the link is too slow for the harmonics, see MIN_RAW_SAMPLES
+    TooFewSamples(usize),
+}
+
+#[derive(Copy, Clone)]
+struct RawPoint {
+    /// microseconds since the start of the capture
+    time: u32,
+    voltage: i32,
+    current: i32,
+}
+
+impl RawPoint {
+    const ZERO: Self = Self {
+        time: 0,
+        voltage: 0,
+        current: 0,
+    };
+}
+
+/// reads the instantaneous voltage and current of `channel` as fast as the
+/// link allows for CAPTURE_CYCLES line cycles, then resamples them to
+/// FFT_LEN points and computes the harmonic content.
+pub async fn capture_harmonics<'a, D: StpmDriver>(
+    chip: &mut Stpm<'a, D>,
+    channel: usize,
+) -> Result<HarmonicsResult, CaptureError<D::Error>> {
+    let (reg_voltage, reg_current) = match channel {
+        0 => (Reg::DSP_REG2, Reg::DSP_REG3),
+        _ => (Reg::DSP_REG4, Reg::DSP_REG5),
+    };
+
+    // the line period decides how long to capture
+    let period = read_latched(chip, Reg::DSP_REG1, Reg::DSP_REG1).await.map_err(CaptureError::Driver)?.0 as u32;
+    let period = match channel {
+        0 => period & 0xfff,
+        _ => (period >> 16) & 0xfff,
+    };
+    if period == 0 {
+        return Err(CaptureError::NoPeriod);
+    }
+    let period_us = period as u64 * 1_000_000 / PERIOD_CLOCK_HZ as u64;
+    let capture_us = period_us * CAPTURE_CYCLES as u64;
+
+    let mut raw = CAPTURE_BUFFER.lock().await;
+    let mut num_samples = 0;
+    let start = Instant::now();
+
+    loop {
+        // the registers are latched by the SYN pulse right after this, the
+        // constant offset does not matter
+        let time = Instant::now().duration_since(start).as_micros();
+        let (voltage, current) = read_latched(chip, reg_voltage, reg_current).await.map_err(CaptureError::Driver)?;
+
+        raw[num_samples] = RawPoint {
+            time: time as u32,
+            voltage,
+            current,
+        };
+        num_samples += 1;
+
+        // one sample past the end, so the last point can be interpolated
+        if time > capture_us && num_samples >= 2 {
+            break;
+        }
+        if num_samples == MAX_RAW_SAMPLES {
+            return Err(CaptureError::BufferFull);
+        }
+    }
+
+    if num_samples < MIN_RAW_SAMPLES {
+        return Err(CaptureError::TooFewSamples(num_samples));
+    }
+
+    let raw = &raw[..num_samples];
+
+    Ok(HarmonicsResult {
+        channel: channel + 1,
+        time: start.as_millis(),
+        num_samples,
+        voltage: analyze(raw, capture_us, |p| p.voltage),
+        current: analyze(raw, capture_us, |p| p.current),
+    })
+}
+
+/// latches the registers with a SYN pulse and reads two of them
+async fn read_latched<'a, D: StpmDriver>(chip: &mut Stpm<'a, D>, reg_a: Reg, reg_b: Reg) -> Result<(i32, i32), D::Error> {
+    chip.driver.syn_pulse().await?;
+
+    let (mut a, mut b) = (0, 0);
+    Reader::create(chip)
+        .read_i32(reg_a, &mut a).await?
+        .read_i32(reg_b, &mut b).await?
+        .end().await?;
+    Ok((a, b))
+}
+
+/// linear interpolation of the raw samples at FFT_LEN equidistant points,
+/// followed by the FFT
+fn analyze(raw: &[RawPoint], capture_us: u64, value: impl Fn(&RawPoint) -> i32) -> Harmonics {
+    let mut re = [0i32; FFT_LEN];
+    let mut im = [0i32; FFT_LEN];
+
+    let mut j = 0;
+    for (i, out) in re.iter_mut().enumerate() {
+        let t = (i as u64 * capture_us / FFT_LEN as u64) as u32;
+        while j + 2 < raw.len() && raw[j + 1].time < t {
+            j += 1;
+        }
+        let (a, b) = (&raw[j], &raw[j + 1]);
+        let dt = b.time.saturating_sub(a.time).max(1) as i64;
+        let frac = (t.saturating_sub(a.time) as i64).min(dt);
+        let (va, vb) = (value(a) as i64, value(b) as i64);
+        *out = (va + (vb - va) * frac / dt) as i32;
+    }
+
+    // keep the values small enough for the fixed point FFT
+    let max = re.iter().map(|v| v.unsigned_abs()).max().unwrap_or(0);
+    let shift = (32 - max.leading_zeros()).saturating_sub(24);
+    for v in re.iter_mut() {
+        *v >>= shift;
+    }
+
+    fft(&mut re, &mut im);
+
+    // harmonic n is in bin n * CAPTURE_CYCLES
+    let amplitude: [u64; NUM_HARMONICS] = core::array::from_fn(|h| {
+        let bin = (h + 1) * CAPTURE_CYCLES;
+        let (r, i) = (re[bin] as i64, im[bin] as i64);
+        isqrt((r * r + i * i) as u64)
+    });
+
+    let fundamental = amplitude[0].max(1);
+    let distortion = isqrt(amplitude[1..].iter().map(|a| a * a).sum());
+
+    Harmonics {
+        magnitudes: amplitude.map(|a| (a * 10000 / fundamental) as u32),
+        thd: (distortion * 10000 / fundamental) as u32,
+    }
+}
+
+/// when to start the next periodic capture
+pub fn next_capture(interval: Option<u32>) -> Option<Instant> {
+    interval.map(|secs| Instant::now() + Duration::from_secs(secs as u64))
+}
+
+/// stores the result for the HTTP API and queues it for MQTT
+pub fn publish_harmonics(result: HarmonicsResult) {
+    LATEST_HARMONICS.lock(|latest| {
+        let mut all = latest.get();
+        all[result.channel - 1] = Some(result);
+        latest.set(all);
+    });
+    // MQTT might not be connected, only keep the newest results
+    if HARMONICS.try_send(result).is_err() {
+        let _ = HARMONICS.try_receive();
+        let _ = HARMONICS.try_send(result);
+    }
+}
@@ -0,0 +1,69 @@
+use embassy_time::{Duration, Instant, Timer};
+
+use super::{
+    chip::{Reader, Reg, Stpm},
+    driver::StpmDriver,
+    events::FinishedEvent,
+    mapping::PowerScale,
+};
+
+/// time between two reads of the momentary power during a capture
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+/// the capture blocks the sample loop, stop well before the energy registers
+/// have to be read again (1.5 s, see `energy::EnergyIntegrator`), even if
+/// both channels capture one after the other
+const MAX_CAPTURE: Duration = Duration::from_millis(300);
+
+/// momentary active power of `channel`, latched with a SYN pulse
+pub async fn read_momentary_power<'a, D: StpmDriver>(chip: &mut Stpm<'a, D>, channel: usize) -> Result<i32, D::Error> {
+    let reg = match channel {
+        0 => Reg::PH1_REG10,
+        _ => Reg::PH2_REG10,
+    };
+
+    chip.driver.syn_pulse().await?;
+
+    let mut power = 0;
+    Reader::create(chip)
+        .read_i32(reg, &mut power).await?
+        .end().await?;
+    Ok(power)
+}
+
+/// follows the momentary power of `channel` after it crossed `threshold`
+/// (raw, scaled to gain 16) until it drops below again or MAX_CAPTURE passed.
+/// the event starts at the call, i.e. at the sample loop tick that saw the
+/// crossing, not at the crossing itself.
+/// `first` is the reading that triggered the capture, `scale` the voltage
+/// mapping correction at that time.
+/// `max` of the result is the peak power, `min` is unused.
+pub async fn capture_inrush<'a, D: StpmDriver>(
+    chip: &mut Stpm<'a, D>,
+    channel: usize,
+    threshold: i64,
+    anti_current_gain: i64,
+    scale: PowerScale,
+    first: i64,
+) -> Result<FinishedEvent, D::Error> {
+    let start = Instant::now();
+    let mut peak = first;
+    let mut end = start;
+
+    while end.duration_since(start) < MAX_CAPTURE {
+        Timer::after(POLL_INTERVAL).await;
+
+        let power = scale.signed(read_momentary_power(chip, channel).await? as i64 * anti_current_gain);
+        end = Instant::now();
+        if power < threshold {
+            break;
+        }
+        peak = peak.max(power);
+    }
+
+    Ok(FinishedEvent {
+        start,
+        duration: end - start,
+        min: 0,
+        max: peak.max(0) as u64,
+    })
+}
@@ -1,23 +1,39 @@
 pub mod calibration;
-mod chip;
-mod driver;
-mod sample;
+pub mod events;
+mod gain;
+mod inrush;
+mod noload;
+pub mod harmonics;
 
-pub use chip::StpmCurrentGain;
-use embassy_futures::select::{select3, Either3};
+use energy_core::stpm::{chip, driver, energy, mapping, sample};
+pub use sample::RawSampleApp;
+pub use chip::{StpmChannelConfiguration, StpmCurrentGain, StpmInterrupts};
+pub use driver::uart::{STPM_UART_BAUD, STPM_UART_BAUD_RANGE};
+pub use energy::EnergyTotals;
+use embassy_futures::select::{select4, Either4};
 
-use crate::{config::{self, StpmConfig, CONFIG_STPM, RESET_ACCUMULATOR}, stpm::sample::{read_samples, RawSampleChip}};
-use chip::{Stpm, StpmChannelConfiguration, StpmConfiguration};
+use crate::{config::{self, calibrate::{Measurement, MEASUREMENT_REQUEST, MEASUREMENT_RESULT}, CalibrationConfig, LineFrequency, StpmConfig, CONFIG_CALIBRATION_STPM, CONFIG_STPM, RESET_ACCUMULATOR}, stpm::sample::{read_samples, signed_phase, RawSampleChip, ReadErrors}, zcr};
+use calibration::{conversion_parameters, FloatCalibration};
+use chip::{Stpm, StpmConfiguration, StpmLineFrequency, EV_CURRENT_SWELL, EV_VOLTAGE_SAG, EV_VOLTAGE_SWELL};
+use energy::EnergyIntegrator;
+use gain::GainRanger;
+use inrush::capture_inrush;
+use mapping::{PowerScale, VoltageMapper};
+use noload::NoLoadDetector;
+use harmonics::{capture_harmonics, next_capture, publish_harmonics, CAPTURE_REQUEST};
+use events::{publish_interrupts, EventTracker, FinishedEvent, StpmEvent, StpmEventKind, StpmInterrupt, EVENTS};
 use core::fmt::Debug;
 use driver::spi::StpmSpiDriver;
 use driver::StpmDriver;
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use core::cell::Cell;
+use core::sync::atomic::{AtomicU32, Ordering};
+use embassy_sync::{blocking_mutex::{raw::CriticalSectionRawMutex, Mutex}, signal::Signal};
 use embassy_time::{Duration, Instant, Ticker, Timer};
 use esp_hal::{
     clock::Clocks,
     dma::{DmaPriority, Spi3DmaChannelCreator},
     dma_descriptors,
-    gpio::{GpioPin, Input, Output, PullUp, PushPull},
+    gpio::{AnyPin, GpioPin, Input, Output, PullUp, PushPull},
     peripherals::SPI3,
     prelude::_fugit_RateExtU32,
     spi::{
@@ -27,19 +43,21 @@ use esp_hal::{
 };
 use esp_println::println;
 
-#[derive(Copy, Clone, Debug, Default)]
-pub struct RawSampleApp {
-    pub voltage_rms: u64,
-    // all raw values are scaled to current gain of 8
-    pub current_rms: u64,
-    pub power_active: i64,
-    pub power_reactive: i64,
-    pub energy_active: i64,
-    pub num_samples: usize,
-}
-
 pub static SAMPLES: Signal<CriticalSectionRawMutex, [RawSampleApp; 2]> = Signal::new();
 
+/// last window sent to SAMPLES, for the HTTP API
+pub static LATEST_SAMPLES: Mutex<CriticalSectionRawMutex, Cell<Option<[RawSampleApp; 2]>>> =
+    Mutex::new(Cell::new(None));
+
+/// line frequency in Hz the chip is configured for
+static LINE_FREQUENCY: AtomicU32 = AtomicU32::new(50);
+
+/// line frequency in Hz the chip is configured for, for conversions that
+/// depend on it
+pub fn line_frequency() -> u32 {
+    LINE_FREQUENCY.load(Ordering::Relaxed)
+}
+
 #[embassy_executor::task]
 pub async fn run_stpm(
     spi3: SPI3,
@@ -65,7 +83,7 @@ pub async fn run_stpm(
             DmaPriority::Priority0,
         ));
 
-    let mut driver = StpmSpiDriver {
+    let mut driver: StpmSpiDriver<_, AnyPin<Output<PushPull>>> = StpmSpiDriver {
         spi_device: spi,
         pin_scs: scs.into(),
         pin_en: en.into(),
@@ -73,18 +91,35 @@ pub async fn run_stpm(
     };
 
     let mut config = CONFIG_STPM.wait().await;
+    let mut calibration = CONFIG_CALIBRATION_STPM.wait().await;
 
-    let mut energy_accumulator = config::read_accumulator().await.unwrap_or([0i64; 2]);
+    // the old firmware stored raw units, convert them with the calibration
+    let legacy_lsb = core::array::from_fn(|i| conversion_parameters(&calibration, i).to_float_cal().energy_lsb_fixed());
+    // totals that can't be read are not overwritten until they are reset on
+    // request, a broken read must not cost the lifetime energy
+    let (mut energy_accumulator, mut accumulator_writable) = match config::read_accumulator(legacy_lsb).await {
+        Ok(totals) => (totals.unwrap_or_default(), true),
+        Err(()) => {
+            println!("not saving the energy totals until they are reset");
+            (Default::default(), false)
+        }
+    };
 
     loop {
-        if None == once_stpm(&mut driver, &mut config, &mut energy_accumulator).await {
+        if None == once_stpm(&mut driver, &mut config, &mut calibration, &mut energy_accumulator, &mut accumulator_writable).await {
             // error -> retry after 0.5 sec
             Timer::after_millis(500).await;
         }
     }
 }
 
-async fn once_stpm<D: StpmDriver>(driver: &mut D, config: &mut StpmConfig, energy_accumulator: &mut [i64; 2]) -> Option<()>
+async fn once_stpm<D: StpmDriver>(
+    driver: &mut D,
+    config: &mut StpmConfig,
+    calibration: &mut CalibrationConfig,
+    energy_accumulator: &mut EnergyTotals,
+    accumulator_writable: &mut bool,
+) -> Option<()>
 where
     D::Error: Debug,
 {
@@ -95,21 +130,46 @@ where
 
     let mut chip = Stpm::new(driver);
 
+    let stpm_line_frequency = resolve_line_frequency(config.line_frequency).await;
+    LINE_FREQUENCY.store(stpm_line_frequency.hz(), Ordering::Relaxed);
+
+    // the configured gain is the start value for auto ranging
+    let mut gain_rangers: [GainRanger; 2] =
+        core::array::from_fn(|i| GainRanger::new(config.current_gain[i], config.auto_current_gain[i]));
+    let mut anti_current_gain = config.current_gain.map(|g| g.anti_gain());
+
+    // the calibration is needed to convert thresholds to register values
+    let float_cal: [FloatCalibration; 2] =
+        core::array::from_fn(|i| conversion_parameters(calibration, i).to_float_cal());
+
     // do not set current / voltage calibration here, as it is only relevant for
     // LED pulse output, instead calibrate everything in software later on
-    let stpm_config = StpmConfiguration {
-        channels: [
-            StpmChannelConfiguration {
-                current_gain: config.current_gain[0],
+    let mut stpm_config = StpmConfiguration {
+        channels: core::array::from_fn(|i| {
+            let mut channel = StpmChannelConfiguration {
+                current_gain: config.current_gain[i],
+                integrator: calibration.channels[i].current_sensor.needs_integrator(),
                 ..Default::default()
-            },
-            StpmChannelConfiguration {
-                current_gain: config.current_gain[1],
-                ..Default::default()
-            },
-        ],
-        ..Default::default()
+            };
+            // a sag threshold of zero disables sag detection
+            if let Some(volts) = config.voltage_sag_threshold[i] {
+                channel.voltage_sag_threshold = float_cal[i].voltage_threshold(volts).max(1);
+            }
+            // a swell threshold of 0x3ff disables swell detection
+            if let Some(volts) = config.voltage_swell_threshold[i] {
+                channel.voltage_swell_threshold = float_cal[i].voltage_threshold(volts).min(0x3fe);
+            }
+            set_current_swell_threshold(&mut channel, config.current_swell_threshold[i], &float_cal[i]);
+            let line_frequency = stpm_line_frequency.hz() as f32;
+            channel.set_phase_compensation(config.phase_compensation[i].to_micros(line_frequency));
+            channel
+        }),
+        line_frequency: stpm_line_frequency,
+        interrupts: config.interrupts,
+        baud_rate: config.uart_baud_rate,
     };
+    let interrupts = config.interrupts;
+    let line_frequency = stpm_config.line_frequency.hz();
 
     if let Err(e) = chip.configure(&stpm_config).await {
         println!("error during stpm configuration {e:?}");
@@ -118,66 +178,263 @@ where
 
     println!("stpm successfully configured");
 
-    let anti_current_gain = config.current_gain.map(|g| match g {
-        StpmCurrentGain::X2 => 8,
-        StpmCurrentGain::X4 => 4,
-        StpmCurrentGain::X8 => 2,
-        StpmCurrentGain::X16 => 1,
-    });
-
     let mut ticker = Ticker::every(Duration::from_millis(50));
-    let mut read_errors = 0u32;
+    let mut read_errors = ReadErrors::default();
 
     let mut raw_samples: [RawSampleChip; 2] = Default::default();
     let mut acc_samples: [RawSampleApp; 2] = Default::default();
     let mut sample_cnt = 0;
-    let mut energy_last = [0u32; 2];
+    // the chip cleared its energy registers during the reset
+    let mut energy_integrators: [EnergyIntegrator; 2] =
+        core::array::from_fn(|i| EnergyIntegrator::new(Instant::now(), float_cal[i].energy_lsb_fixed()));
+    let inrush_thresholds: [Option<i64>; 2] =
+        core::array::from_fn(|i| config.inrush_threshold[i].map(|watts| float_cal[i].power_to_raw(watts)));
+    let no_load_detectors: [NoLoadDetector; 2] =
+        core::array::from_fn(|i| NoLoadDetector::new(&config.no_load_threshold[i], &float_cal[i]));
+    let voltage_mappers: [VoltageMapper; 2] =
+        core::array::from_fn(|i| VoltageMapper::new(i, &config.voltage_mapping[i], float_cal.map(|cal| cal.voltage_rms_lsb)));
+    let mut power_scales = [Some(PowerScale::ONE); 2];
+    // a capture that ran into its time limit must not trigger again right away
+    let mut inrush_armed = [true; 2];
+    let mut sag_trackers: [EventTracker; 2] = Default::default();
+    let mut swell_trackers: [EventTracker; 2] = Default::default();
+    let mut current_swell_trackers: [EventTracker; 2] = Default::default();
 
     let mut accumulator_last_write = Instant::now();
+    let mut measurement: Option<Measurement> = None;
+    let mut harmonics_next = next_capture(config.harmonics_interval);
+
+    // start from a clean status, anything set during configuration is meaningless
+    if let Err(e) = chip.read_clear_status().await {
+        println!("error clearing stpm status {e:?}");
+        return None;
+    }
 
     loop {
         // check if there is a new configuration / wait for ticker
-        match select3(CONFIG_STPM.wait(), RESET_ACCUMULATOR.wait(), ticker.next()).await {
-            Either3::First(new_config) => {
+        match select4(CONFIG_STPM.wait(), CONFIG_CALIBRATION_STPM.wait(), RESET_ACCUMULATOR.wait(), ticker.next()).await {
+            Either4::First(new_config) => {
                 *config = new_config;
                 return Some(());
             },
-            Either3::Second(_) => {
-                *energy_accumulator = [0; 2];
+            Either4::Second(new_calibration) => {
+                *calibration = new_calibration;
+                return Some(());
+            },
+            Either4::Third(_) => {
+                *energy_accumulator = Default::default();
+                *accumulator_writable = true;
                 continue;
             },
-            Either3::Third(_) => (),
+            Either4::Fourth(_) => (),
+        };
+
+        // INT1 is not routed to the ESP, the selected status bits are latched
+        // by the chip and read every tick
+        if interrupts.any() {
+            let status = match chip.read_clear_status().await {
+                Ok(status) => status,
+                Err(e) => {
+                    println!("stpm error reading status: {e:?}");
+                    if read_errors.exhausted() {
+                        return None;
+                    }
+                    continue;
+                }
+            };
+            let now = Instant::now();
+            publish_interrupts(&status, now);
+
+            // sag / swell edges from the status registers drive the trackers
+            // directly, the latched bits also catch events shorter than a tick
+            for i in 0..2 {
+                let sr = status.dsp[i];
+                let voltage = raw_samples[i].voltage_rms as u64;
+                let current = raw_samples[i].current_rms as u64 * anti_current_gain[i] as u64;
+
+                for (interrupt, bit) in StpmInterrupt::DSP {
+                    if sr & bit == 0 {
+                        continue;
+                    }
+                    let (tracker, kind, active, value) = match interrupt {
+                        StpmInterrupt::VoltageSagStart => (&mut sag_trackers[i], StpmEventKind::VoltageSag, true, voltage),
+                        StpmInterrupt::VoltageSagEnd => (&mut sag_trackers[i], StpmEventKind::VoltageSag, false, voltage),
+                        StpmInterrupt::VoltageSwellStart => (&mut swell_trackers[i], StpmEventKind::VoltageSwell, true, voltage),
+                        StpmInterrupt::VoltageSwellEnd => (&mut swell_trackers[i], StpmEventKind::VoltageSwell, false, voltage),
+                        StpmInterrupt::CurrentSwellStart => (&mut current_swell_trackers[i], StpmEventKind::CurrentSwell, true, current),
+                        StpmInterrupt::CurrentSwellEnd => (&mut current_swell_trackers[i], StpmEventKind::CurrentSwell, false, current),
+                        _ => continue,
+                    };
+                    if kind == StpmEventKind::CurrentSwell {
+                        acc_samples[i].current_swell = true;
+                    }
+                    if let Some(event) = tracker.update(active, value, 0) {
+                        let value = if kind == StpmEventKind::VoltageSag { event.min } else { event.max };
+                        send_event(i, kind, value, event);
+                    }
+                }
+            }
         }
 
         // try read
-        if let Err(e) = read_samples(&mut chip, &mut raw_samples).await {
-            if read_errors >= 3 {
+        if let Err(e) = read_samples(&mut chip, &mut raw_samples, !interrupts.covers_events()).await {
+            if read_errors.exhausted() {
                 println!("stpm too many error reading samples, restarting: {e:?}");
                 return None;
             } else {
                 println!("stpm error reading samples: {e:?}");
-                read_errors += 1;
                 continue;
             }
         }
 
-        read_errors = read_errors.saturating_sub(1);
+        read_errors.succeeded();
+
+        // follow sag / swell events that are not reported by interrupt
+        for i in 0..2 {
+            let raw = &raw_samples[i];
+            let voltage = raw.voltage_rms as u64;
+
+            if !interrupts.voltage_sag {
+                let sag = raw.events & EV_VOLTAGE_SAG != 0;
+                if let Some(event) = sag_trackers[i].update(sag, voltage, chip::sag_time(raw.voltage_event_time)) {
+                    send_event(i, StpmEventKind::VoltageSag, event.min, event);
+                }
+            }
+
+            if !interrupts.voltage_swell {
+                let swell = raw.events & EV_VOLTAGE_SWELL != 0;
+                if let Some(event) = swell_trackers[i].update(swell, voltage, chip::swell_time(raw.voltage_event_time)) {
+                    send_event(i, StpmEventKind::VoltageSwell, event.max, event);
+                }
+            }
+
+            let current = raw.current_rms as u64 * anti_current_gain[i] as u64;
+            if !interrupts.current_swell {
+                let current_swell = raw.events & EV_CURRENT_SWELL != 0;
+                if let Some(event) = current_swell_trackers[i].update(current_swell, current, chip::swell_time(raw.current_event_time)) {
+                    send_event(i, StpmEventKind::CurrentSwell, event.max, event);
+                }
+                acc_samples[i].current_swell |= current_swell;
+            } else {
+                // still in a swell that started in an earlier window
+                acc_samples[i].current_swell |= current_swell_trackers[i].is_active();
+            }
+
+            // the trackers keep min / max up to date while the event is active
+            if interrupts.voltage_sag {
+                sag_trackers[i].observe(voltage);
+            }
+            if interrupts.voltage_swell {
+                swell_trackers[i].observe(voltage);
+            }
+            if interrupts.current_swell {
+                current_swell_trackers[i].observe(current);
+            }
+        }
+
+        // pair the currents with their configured voltages, the events
+        // above still follow the voltage inputs
+        let voltages = raw_samples.map(|raw| raw.voltage_rms);
+        for i in 0..2 {
+            power_scales[i] = voltage_mappers[i].apply(&mut raw_samples[i], voltages, i);
+        }
 
         // accumulate everything
+        let mut active_energy = [None; 2];
         for i in 0..2 {
             let raw = &mut raw_samples[i];
             let acc = &mut acc_samples[i];
+            // without the own voltage the mapped powers are unknown, the energy
+            // of this reading is dropped
+            let scale = power_scales[i].unwrap_or(PowerScale::ZERO);
+            if power_scales[i].is_none() {
+                acc.mapping_failed = true;
+                acc.energy_gap = true;
+            }
+
+            // suppress the noise without load before anything else sees it,
+            // except for the offset calibration that measures this noise. the
+            // energy totals stay frozen either way.
+            let mut detected = *raw;
+            let no_load = no_load_detectors[i].apply(&mut detected, anti_current_gain[i]);
+            if !measurement.as_ref().is_some_and(|m| m.bypasses_no_load(i)) {
+                *raw = detected;
+                acc.no_load_active += no_load.active as usize;
+                acc.no_load_reactive += no_load.reactive as usize;
+                acc.no_load_current += no_load.current as usize;
+            }
 
-            acc.current_rms += raw.current_rms as u64;
+            // the gain can change within a window, normalize every sample
+            let current_rms = raw.current_rms as u64 * anti_current_gain[i] as u64;
+            let power_active = scale.signed(raw.power_active as i64 * anti_current_gain[i]);
+            acc.current_rms += current_rms;
             acc.voltage_rms += raw.voltage_rms as u64;
-            acc.power_active += raw.power_active as i64;
-            acc.power_reactive += raw.power_reactive as i64;
+            acc.current_rms_sq += current_rms as u128 * current_rms as u128;
+            acc.voltage_rms_sq += raw.voltage_rms as u128 * raw.voltage_rms as u128;
+            acc.power_active += power_active;
+            acc.voltage_rms_range.update(raw.voltage_rms as u64, sample_cnt == 0);
+            acc.current_rms_range.update(current_rms, sample_cnt == 0);
+            acc.power_active_range.update(power_active, sample_cnt == 0);
+            acc.power_reactive += scale.signed(raw.power_reactive as i64 * anti_current_gain[i]);
+            acc.power_apparent += scale.unsigned(raw.power_apparent as i64 * anti_current_gain[i]);
+            acc.power_fundamental += scale.signed(raw.power_fundamental as i64 * anti_current_gain[i]);
+            acc.phase_angle += signed_phase(raw.phase_angle, line_frequency) as i64;
+            // the period reads zero without voltage
+            if raw.period != 0 {
+                acc.period += raw.period as u64;
+                acc.period_samples += 1;
+            }
             
             // accumulate total energy in external (to this function) variables
-            let energy_diff = raw.energy_active.wrapping_sub(energy_last[i]) as i32 as i64;
-            energy_last[i] = raw.energy_active;
+            active_energy[i] = energy_integrators[i].update(Instant::now(), raw, anti_current_gain[i], scale, no_load, &mut energy_accumulator.channels[i]);
+            if active_energy[i].is_none() {
+                println!("stpm channel {} energy registers not read in time, dropping energy", i + 1);
+                acc.energy_gap = true;
+            }
+        }
+        energy_accumulator.add_combined(active_energy);
+
+        // inrush: follow the momentary power closely once it crosses the threshold
+        for i in 0..2 {
+            // the mapped power is unknown without the own voltage
+            let (Some(threshold), Some(scale)) = (inrush_thresholds[i], power_scales[i]) else {
+                continue;
+            };
+            let power = scale.signed(raw_samples[i].power_momentary as i64 * anti_current_gain[i]);
+            if power < threshold {
+                inrush_armed[i] = true;
+                continue;
+            }
+            if !inrush_armed[i] {
+                continue;
+            }
+            inrush_armed[i] = false;
+            match capture_inrush(&mut chip, i, threshold, anti_current_gain[i], scale, power).await {
+                Ok(event) => send_event(i, StpmEventKind::Inrush, event.max, event),
+                Err(e) => println!("stpm error during inrush capture: {e:?}"),
+            }
+            // don't catch up on the ticks missed during the capture
+            ticker.reset();
+        }
+
+        // auto ranging, right after reading the energies so the next energy
+        // difference is almost completely measured at the new gain
+        for i in 0..2 {
+            let Some(new_gain) = gain_rangers[i].update(raw_samples[i].current_rms) else {
+                continue;
+            };
+            let channel = &mut stpm_config.channels[i];
+            channel.current_gain = new_gain;
+            anti_current_gain[i] = new_gain.anti_gain();
+            set_current_swell_threshold(channel, config.current_swell_threshold[i], &float_cal[i]);
+            if let Err(e) = chip.set_current_gain(i, channel).await {
+                println!("stpm error switching current gain: {e:?}");
+                return None;
+            }
+        }
 
-            energy_accumulator[i] += energy_diff * anti_current_gain[i];
+        if let Some(request) = MEASUREMENT_REQUEST.try_take() {
+            measurement = Some(Measurement::new(request));
         }
 
         sample_cnt += 1;
@@ -185,24 +442,102 @@ where
         if sample_cnt >= config.samples_stpm {
             // update things
             for i in 0..2 {
-                // apply anti-gain
-                acc_samples[i].current_rms *= anti_current_gain[i] as u64;
-                acc_samples[i].power_active *= anti_current_gain[i];
-                acc_samples[i].power_reactive *= anti_current_gain[i];
                 // update other values
-                acc_samples[i].energy_active = energy_accumulator[i];
+                acc_samples[i].energy_active = energy_accumulator.channels[i].active.value;
+                acc_samples[i].energy_fundamental = energy_accumulator.channels[i].fundamental.value;
+                acc_samples[i].energy_reactive = energy_accumulator.channels[i].reactive.value;
+                acc_samples[i].energy_apparent = energy_accumulator.channels[i].apparent.value;
+                acc_samples[i].energy_import = energy_accumulator.channels[i].active_import.value;
+                acc_samples[i].energy_export = energy_accumulator.channels[i].active_export.value;
+                acc_samples[i].energy_combined_import = energy_accumulator.combined_import.value;
+                acc_samples[i].energy_combined_export = energy_accumulator.combined_export.value;
+                acc_samples[i].current_gain = gain_rangers[i].gain;
                 acc_samples[i].num_samples = config.samples_stpm;
+                acc_samples[i].rms_mode = config.rms_mode;
             }
             // send to MQTT
             SAMPLES.signal(acc_samples);
+            LATEST_SAMPLES.lock(|latest| latest.set(Some(acc_samples)));
+            // calibration measurement
+            if let Some(sum) = measurement.as_mut().and_then(|m| m.add_window(&acc_samples)) {
+                MEASUREMENT_RESULT.signal(sum);
+                measurement = None;
+            }
             // reset
             sample_cnt = 0;
             acc_samples = Default::default();
         }
 
-        if Instant::now().duration_since(accumulator_last_write).as_millis() > 1000 {
+        if *accumulator_writable && Instant::now().duration_since(accumulator_last_write).as_millis() > 1000 {
             let _ = config::write_accumulator(energy_accumulator).await;
             accumulator_last_write = Instant::now();
         }
+
+        // waveform capture, on request or periodically for both channels
+        let mut capture = [false; 2];
+        if let Some(channel) = CAPTURE_REQUEST.try_take() {
+            capture[channel.min(1)] = true;
+        }
+        if harmonics_next.is_some_and(|next| Instant::now() >= next) {
+            capture = [true; 2];
+            harmonics_next = next_capture(config.harmonics_interval);
+        }
+        for i in 0..2 {
+            if !capture[i] {
+                continue;
+            }
+            match capture_harmonics(&mut chip, i).await {
+                Ok(result) => publish_harmonics(result),
+                Err(e) => println!("stpm error during waveform capture: {e:?}"),
+            }
+            ticker.reset();
+        }
+    }
+}
+
+/// picks the line frequency of the chip. with auto detection, waits up to
+/// 2 seconds for a ZCR measurement and falls back to 50 Hz without one.
+async fn resolve_line_frequency(setting: LineFrequency) -> StpmLineFrequency {
+    match setting {
+        LineFrequency::F50 => StpmLineFrequency::F50,
+        LineFrequency::F60 => StpmLineFrequency::F60,
+        LineFrequency::Auto => {
+            for _ in 0..20 {
+                // 4 decimal places, split halfway between 50 and 60 Hz
+                if let Some(frequency) = zcr::get_frequency_unchecked() {
+                    println!("detected line frequency {frequency}");
+                    return if frequency < 55_0000 {
+                        StpmLineFrequency::F50
+                    } else {
+                        StpmLineFrequency::F60
+                    };
+                }
+                Timer::after_millis(100).await;
+            }
+            println!("no ZCR signal, assuming 50 Hz line frequency");
+            StpmLineFrequency::F50
+        }
+    }
+}
+
+/// the current swell threshold register depends on the current gain
+fn set_current_swell_threshold(channel: &mut StpmChannelConfiguration, amps: Option<f32>, float_cal: &FloatCalibration) {
+    channel.current_swell_threshold = match amps {
+        Some(amps) => float_cal.current_threshold(amps, channel.current_gain.anti_gain()).min(0x3fe),
+        // 0x3ff disables swell detection
+        None => 0x3ff,
+    };
+}
+
+fn send_event(channel: usize, kind: StpmEventKind, value: u64, event: FinishedEvent) {
+    let event = StpmEvent {
+        channel,
+        kind,
+        start: event.start,
+        duration: event.duration,
+        value,
+    };
+    if EVENTS.try_send(event).is_err() {
+        println!("stpm event queue full, dropping {event:?}");
     }
 }
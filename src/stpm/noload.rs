@@ -0,0 +1,56 @@
+pub use energy_core::stpm::noload::NoLoad;
+
+use crate::config::NoLoadThreshold;
+
+use super::{calibration::FloatCalibration, sample::RawSampleChip};
+
+/// no-load thresholds of a channel as raw values at gain 16
+pub struct NoLoadDetector {
+    current: Option<u64>,
+    active: Option<i64>,
+    reactive: Option<i64>,
+}
+
+impl NoLoadDetector {
+    pub fn new(threshold: &NoLoadThreshold, float_cal: &FloatCalibration) -> Self {
+        Self {
+            current: threshold.current.map(|amps| float_cal.current_to_raw(amps) as u64),
+            active: threshold.active_power.map(|watts| float_cal.power_to_raw(watts)),
+            reactive: threshold.reactive_power.map(|vars| float_cal.power_to_raw(vars)),
+        }
+    }
+
+    /// zeroes the values of `raw` that are below their threshold
+    pub fn apply(&self, raw: &mut RawSampleChip, anti_current_gain: i64) -> NoLoad {
+        let current = raw.current_rms as u64 * anti_current_gain as u64;
+        let below = |power: i32, threshold: Option<i64>| {
+            threshold.is_some_and(|threshold| (power as i64 * anti_current_gain).abs() < threshold)
+        };
+
+        let no_load = match self.current {
+            Some(threshold) if current < threshold => NoLoad {
+                current: true,
+                active: true,
+                reactive: true,
+            },
+            _ => NoLoad {
+                current: false,
+                active: below(raw.power_active, self.active),
+                reactive: below(raw.power_reactive, self.reactive),
+            },
+        };
+
+        if no_load.current {
+            raw.current_rms = 0;
+            raw.power_apparent = 0;
+            raw.power_fundamental = 0;
+        }
+        if no_load.active {
+            raw.power_active = 0;
+        }
+        if no_load.reactive {
+            raw.power_reactive = 0;
+        }
+        no_load
+    }
+}
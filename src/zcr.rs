@@ -1,3 +1,6 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_time::{Duration, Instant};
 use esp_hal::{
     gpio::{GpioPin, Input, InputPin, InputSignal, PullUp}, interrupt::{self, Priority}, macros::interrupt, mcpwm::PwmPeripheral, peripherals::{Interrupt, MCPWM0}
 };
@@ -8,6 +11,22 @@ pub static mut ZCR_TDIFF: u32 = 0;
 pub const ZCR_COUNT: usize = 64;
 /// divide this by ZCR_TDIFF to get frequency with 4 decimal places
 pub static mut ZCR_FREQUENCY_NUMERATOR: u64 = 0;
+/// embassy time in milliseconds at the last zero crossing, wraps after 49
+/// days. a u64 could tear when the interrupt hits between the two halves.
+static ZCR_LAST_MILLIS: AtomicU32 = AtomicU32::new(0);
+
+/// without a zero crossing for this long, the ZCR signal is considered missing
+const ZCR_TIMEOUT: Duration = Duration::from_millis(100);
+/// frequencies deviating more than this from the nominal line frequency are
+/// implausible, in percent
+const FREQUENCY_TOLERANCE: u64 = 15;
+
+/// `frequency` with 4 decimal places is close enough to the nominal line
+/// frequency
+pub fn is_plausible(frequency: u64) -> bool {
+    let nominal = crate::stpm::line_frequency() as u64 * 1_0000;
+    frequency.abs_diff(nominal) <= nominal * FREQUENCY_TOLERANCE / 100
+}
 
 /// holds the timer value at the last x zero crossings
 static mut ZCR_TIMES: [u32; ZCR_COUNT] = [0; ZCR_COUNT];
@@ -31,16 +50,32 @@ unsafe fn MCPWM0() {
 
     let tdiff = time.wrapping_sub(time_last);
     core::ptr::write_volatile(&mut ZCR_TDIFF, tdiff);
+    ZCR_LAST_MILLIS.store(Instant::now().as_millis() as u32, Ordering::Release);
 }
 
-/// get current line frequency with 4 decimal places
-pub fn get_frequency() -> u64 {
+/// get current line frequency with 4 decimal places, None if the ZCR signal
+/// is missing or the result is implausible
+pub fn get_frequency() -> Option<u64> {
+    get_frequency_unchecked().filter(|f| is_plausible(*f))
+}
+
+/// like `get_frequency`, but without checking against the nominal line
+/// frequency
+pub fn get_frequency_unchecked() -> Option<u64> {
     let zcr_frequency_numerator =
         unsafe { core::ptr::read_volatile(&ZCR_FREQUENCY_NUMERATOR) } as u64;
     // in microseconds
     let zcr_tdiff = unsafe { core::ptr::read_volatile(&ZCR_TDIFF) } as u64;
+    let last = ZCR_LAST_MILLIS.load(Ordering::Acquire);
+    let elapsed = (Instant::now().as_millis() as u32).wrapping_sub(last);
+
+    // not enough zero crossings yet, or the signal stopped
+    if zcr_tdiff == 0 || elapsed as u64 > ZCR_TIMEOUT.as_millis() {
+        return None;
+    }
+
     // 4 decimal places
-    zcr_frequency_numerator / zcr_tdiff
+    Some(zcr_frequency_numerator / zcr_tdiff)
 }
 
 pub fn zcr_setup(mut zcr_input: GpioPin<Input<PullUp>, 21>, mcpwm: MCPWM0, num_samples: usize, frequency_adjust_esp: f32)